@@ -1,8 +1,16 @@
+use crate::flags;
+
 pub struct ActionOrdering {
-  actions: [(u64, u32); 7],
+  actions: [(u64, u64); 7],
   size: usize,
 }
 
+impl Default for ActionOrdering {
+  fn default() -> Self {
+      Self::new()
+  }
+}
+
 impl ActionOrdering {
   pub fn new() -> Self {
       Self {
@@ -11,7 +19,7 @@ impl ActionOrdering {
       }
   }
 
-  pub fn push(&mut self, action: u64, score: u32) {
+  pub fn push(&mut self, action: u64, score: u64) {
       let mut i = self.size;
       while i > 0 && score < self.actions[i - 1].1 {
           self.actions[i] = self.actions[i - 1];
@@ -33,3 +41,58 @@ impl Iterator for ActionOrdering {
       Some(self.actions[self.size].0)
   }
 }
+
+/// Sources of move ordering used on top of the threats each move makes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MoveOrdering {
+  /// Tries first the move found best when the position was last searched,
+  /// as stored in the transposition table.
+  pub tt_move: bool,
+  /// Breaks ties between moves making as many threats in favour of the last
+  /// two moves causing a cutoff with as many moves played.
+  pub killers: bool,
+  /// Breaks the ties left by how much the moves caused cutoffs for the same
+  /// player during the search.
+  pub history: bool,
+}
+
+impl MoveOrdering {
+  pub const ALL: MoveOrdering = MoveOrdering { tt_move: true, killers: true, history: true };
+}
+
+impl std::str::FromStr for MoveOrdering {
+  type Err = String;
+
+  /// Parses `none`, `all` or a comma separated list of `tt`, `killers` and `history`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+      let [tt_move, killers, history] = flags::parse(s, "move ordering", ["tt", "killers", "history"])?;
+      Ok(MoveOrdering { tt_move, killers, history })
+  }
+}
+
+impl std::fmt::Display for MoveOrdering {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+      flags::format(f, [(self.tt_move, "tt"), (self.killers, "killers"), (self.history, "history")])
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_ordering() {
+      let mut ordering = ActionOrdering::new();
+      for (action, score) in [(1, 0), (2, 5), (4, 0), (8, 1 << 40)] { ordering.push(action, score); }
+      assert_eq!(ordering.collect::<Vec<u64>>(), vec![8, 2, 4, 1]);
+  }
+
+  #[test]
+  fn test_move_ordering() {
+      assert_eq!("none".parse(), Ok(MoveOrdering::default()));
+      assert_eq!("all".parse::<MoveOrdering>().unwrap().to_string(), "tt,killers,history");
+      assert_eq!("history,tt".parse(), Ok(MoveOrdering { tt_move: true, killers: false, history: true }));
+      assert!("tt,pv".parse::<MoveOrdering>().is_err());
+      assert_eq!("tt,pv".parse::<MoveOrdering>().unwrap_err(), "unknown move ordering pv, expected none, all or a list of tt, killers and history");
+  }
+}
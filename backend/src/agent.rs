@@ -1,139 +1,845 @@
 // todo: potentially pass transposition table as a parameter to agent
 
-use crate::board::Board;
-use crate::opening_db::OpeningDatabase;
-use crate::transposition::TranspositionTable;
-use crate::action_ordering::ActionOrdering;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const MIN_SCORE: i8 = -18;
-const MAX_SCORE: i8 = 18;
+use crate::board::{Board, WIDTH};
+use crate::opening_db::{OpeningDatabase, SharedBook};
+use crate::transposition::{Bound, TranspositionTable};
+use crate::action_ordering::{ActionOrdering, MoveOrdering};
+use crate::knowledge;
+use crate::flags;
+#[cfg(feature = "nn")]
+use crate::nn::Network;
+use crate::progress::{CancellationToken, Progress, SearchLimits, SearchObserver};
+use crate::stats::SearchStats;
+
+const COLUMN_ORDER: [usize; WIDTH] = [3, 2, 4, 1, 5, 0, 6];
+const NO_KILLER: u8 = !0;
+// empty tiles up to which the endgame checks pay off
+const ENDGAME_TILES: usize = 16;
+// moves played up to which the knowledge-based rules are tried, deeper in the
+// tree a failed proof costs far more than the nodes a proven one saves
+const KNOWLEDGE_PLIES: usize = 12;
+
+// nodes searched between checks of the cancellation token and progress reports
+const POLL_INTERVAL: u64 = 4096;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A search stopped by its cancellation token, or for running out of its
+/// `SearchLimits`, before the score was proven.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cancelled {
+    /// Bounds on the score proven before stopping.
+    pub min: i8,
+    pub max: i8,
+    /// The best column found so far, when choosing a move.
+    pub col: Option<u8>,
+    /// Scores of the columns solved before stopping, when choosing a move.
+    pub scores: [Option<i8>; WIDTH],
+    pub nodes: u64,
+    pub elapsed: Duration,
+}
+
+/// Pruning techniques used on top of the alpha-beta search, the
+/// transposition table and the anticipation of losing moves.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pruning {
+    /// Enhanced transposition cutoffs: looks up each move's position in the
+    /// transposition table before searching any of them.
+    pub etc: bool,
+    /// Scores a move leaving two threats the opponent cannot both stop as a
+    /// win, without searching it.
+    pub double_threats: bool,
+    /// With few empty tiles left, bounds the score at a draw when a player
+    /// cannot complete any four in a row anymore.
+    pub endgame: bool,
+}
+
+impl Pruning {
+    pub const ALL: Pruning = Pruning { etc: true, double_threats: true, endgame: true };
+}
+
+impl FromStr for Pruning {
+    type Err = String;
+
+    /// Parses `none`, `all` or a comma separated list of `etc`, `threats` and `endgame`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [etc, double_threats, endgame] = flags::parse(s, "pruning", ["etc", "threats", "endgame"])?;
+        Ok(Pruning { etc, double_threats, endgame })
+    }
+}
+
+impl fmt::Display for Pruning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        flags::format(f, [(self.etc, "etc"), (self.double_threats, "threats"), (self.endgame, "endgame")])
+    }
+}
 
 #[derive(Clone)]
-pub struct Agent<'a> {
-    opening_db: Option<&'a OpeningDatabase>,
+pub struct Agent {
+    book: SharedBook,
+    // the book as of the start of the current search
+    opening_db: Option<Arc<OpeningDatabase>>,
     transposition_table: TranspositionTable,
-    visited: u64,
+    stats: SearchStats,
+    knowledge: bool,
+    ordering: MoveOrdering,
+    pruning: Pruning,
+    // the last two columns causing a cutoff by moves played, and how much
+    // each player's moves caused cutoffs by cell, during the current search
+    killers: [[u8; 2]; 42],
+    history: [[u32; 49]; 2],
+    // the network ordering moves, and the moves from the start up to which it does
+    #[cfg(feature = "nn")]
+    evaluator: Option<(Arc<Network>, usize)>,
+    observer: Option<Arc<dyn SearchObserver>>,
+    cancel_token: Option<CancellationToken>,
+    limits: SearchLimits,
+    budget: Budget,
+    search: SearchState,
 }
 
-impl<'a> Agent<'a> {
-    pub fn new(opening_db: Option<&'a OpeningDatabase>) -> Agent {
+// what is left of the limits of the current call, checked along with the
+// cancellation token
+#[derive(Clone, Copy, Default)]
+struct Budget {
+    deadline: Option<Instant>,
+    // nodes the current score search may visit
+    nodes: Option<u64>,
+}
+
+// bookkeeping for progress reports and cancellation during one search
+#[derive(Clone, Copy)]
+struct SearchState {
+    start: Instant,
+    last_report: Instant,
+    col: Option<u8>,
+    min: i8,
+    max: i8,
+    cancelled: bool,
+}
+
+impl Agent {
+    pub fn new(opening_db: Option<Arc<OpeningDatabase>>) -> Agent {
         Agent {
-            opening_db: opening_db,
+            book: SharedBook::new(opening_db.clone()),
+            opening_db,
             transposition_table: TranspositionTable::new(),
-            visited: 0,
+            stats: SearchStats::default(),
+            knowledge: false,
+            ordering: MoveOrdering::default(),
+            pruning: Pruning::default(),
+            killers: [[NO_KILLER; 2]; 42],
+            history: [[0; 49]; 2],
+            #[cfg(feature = "nn")]
+            evaluator: None,
+            observer: None,
+            cancel_token: None,
+            limits: SearchLimits::default(),
+            budget: Budget::default(),
+            search: SearchState {
+                start: Instant::now(),
+                last_report: Instant::now(),
+                col: None,
+                min: 0,
+                max: 0,
+                cancelled: false,
+            },
         }
     }
 
-    pub fn best_col(&mut self, board: Board) -> (u8, i8) {
-        let mut col: u8 = 0;
-        let mut score = -127;
+    /// Consults the knowledge-based rules during search, bounding the score
+    /// at a draw whenever they prove the second player cannot lose. They are
+    /// only tried in the first 12 moves of the game, where they pay off.
+    pub fn with_knowledge(mut self, enabled: bool) -> Self {
+        self.knowledge = enabled;
+        self
+    }
+
+    /// Orders moves by the score `network` gives the positions they lead to
+    /// at nodes up to `max_ply` moves from the start, and scores positions
+    /// at the horizon of `estimate` with it.
+    #[cfg(feature = "nn")]
+    pub fn with_evaluator(mut self, network: Option<Arc<Network>>, max_ply: usize) -> Self {
+        self.evaluator = network.map(|network| (network, max_ply));
+        self
+    }
+
+    /// Orders moves with the sources selected on top of the threats they make.
+    pub fn with_ordering(mut self, ordering: MoveOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Prunes the search with the techniques selected.
+    pub fn with_pruning(mut self, pruning: Pruning) -> Self {
+        self.pruning = pruning;
+        self
+    }
+
+    /// Uses whatever database `book` holds at the start of each search, so
+    /// swapping it updates every agent sharing it.
+    pub fn with_book(mut self, book: SharedBook) -> Self {
+        self.opening_db = book.get();
+        self.book = book;
+        self
+    }
+
+    /// Replaces the transposition table with one of at least `entries`
+    /// entries, see `TranspositionTable::with_size`.
+    pub fn with_table_size(mut self, entries: usize) -> Self {
+        self.transposition_table = TranspositionTable::with_size(entries);
+        self
+    }
+
+    /// Number of entries in the transposition table.
+    pub fn table_size(&self) -> usize {
+        self.transposition_table.size()
+    }
+
+    /// Reports progress of following searches to `observer` whenever the
+    /// score bounds narrow, and at least every 100ms.
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn SearchObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Following searches stop with `Cancelled` once `token` is cancelled.
+    pub fn set_cancel_token(&mut self, token: Option<CancellationToken>) {
+        self.cancel_token = token;
+    }
+
+    /// Following searches stop with `Cancelled` once they exceed `limits`,
+    /// which apply to each call as a whole.
+    pub fn set_limits(&mut self, limits: SearchLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> SearchLimits {
+        self.limits
+    }
+
+    pub fn best_col(&mut self, board: Board) -> Result<(u8, i8, SearchStats), Cancelled> {
+        let (scores, stats) = self.solve_columns(board, true)?;
+        let (col, score) = best_column(&scores).unwrap_or((0, -127));
+        Ok((col, score, stats))
+    }
+
+    /// Solves every playable column, scored from the side to move's point of view.
+    pub fn column_scores(&mut self, board: Board) -> Result<([Option<i8>; WIDTH], SearchStats), Cancelled> {
+        self.solve_columns(board, false)
+    }
+
+    // `stop_on_win` skips the remaining columns once a winning move is found
+    fn solve_columns(&mut self, board: Board, stop_on_win: bool) -> Result<([Option<i8>; WIDTH], SearchStats), Cancelled> {
+        self.opening_db = self.book.get();
+        let start = Instant::now();
+        self.budget.deadline = self.limits.time.map(|time| start + time);
+        let mut scores = [None; WIDTH];
+        let mut stats = SearchStats::default();
 
         let action_mask = board.playable_tile_mask();
-        for i in [3, 2, 4, 1, 5, 0, 6] {
-            let action = Board::get_action(action_mask, i);
-            if action != 0 {
-                if board.is_winning_action(action) { 
-                    return (i as u8, 21 - (board.num_actions() as i8) / 2); 
+        for i in COLUMN_ORDER {
+            let action = Board::get_action(action_mask, i as i32);
+            if action == 0 { continue; }
+
+            let winning = board.is_winning_action(action);
+            let score = if winning { 21 - (board.num_actions() as i8) / 2 } else {
+                self.budget.nodes = self.limits.nodes.map(|nodes| nodes.saturating_sub(stats.nodes));
+                match self.search_score(board.make_action(action), Some(i as u8)) {
+                    Ok((child_score, child_stats)) => {
+                        stats += child_stats;
+                        -child_score
+                    },
+                    Err(cancelled) => {
+                        let best = best_column(&scores);
+                        return Err(Cancelled {
+                            min: best.map_or(-127, |(_, score)| score).max(-(42 - board.num_actions() as i8) / 2),
+                            max: (43 - board.num_actions() as i8) / 2,
+                            col: best.map(|(col, _)| col),
+                            scores,
+                            nodes: stats.nodes + cancelled.nodes,
+                            elapsed: start.elapsed(),
+                        });
+                    },
                 }
+            };
+
+            scores[i] = Some(score);
+            if let Some(ref observer) = self.observer { observer.on_column(i as u8, score); }
+            if winning && stop_on_win { break; }
+        }
 
-                let child = board.make_action(action);
-                let child_score = -self.best_score(child).0;
+        stats.elapsed = start.elapsed();
+        Ok((scores, stats))
+    }
 
-                if child_score > score {
-                    score = child_score;
-                    col = i as u8;
-                }
+    /// The line both sides play from `board` when each picks its best move,
+    /// up to the end of the game, with the stats of all its searches. The
+    /// whole line is searched within `limits`.
+    pub fn principal_variation(&mut self, mut board: Board, limits: SearchLimits) -> Result<(Vec<u8>, SearchStats), Cancelled> {
+        let saved = self.limits;
+        let start = Instant::now();
+        let mut total = SearchStats::default();
+        let mut pv = Vec::new();
+        while !board.status().is_over() {
+            // each move gets what the previous ones left
+            self.limits = limits.remaining(total.nodes, start.elapsed());
+            match self.best_col(board) {
+                Ok((col, _, stats)) => {
+                    total += stats;
+                    pv.push(col);
+                    board.play_col(col as usize);
+                },
+                Err(cancelled) => {
+                    self.limits = saved;
+                    return Err(Cancelled { nodes: total.nodes + cancelled.nodes, elapsed: start.elapsed(), ..cancelled });
+                },
             }
         }
 
-        (col, score)
+        self.limits = saved;
+        total.elapsed = start.elapsed();
+        Ok((pv, total))
     }
 
-    pub fn best_score(&mut self, board: Board) -> (i8, u64) {
-        if board.has_winning_action() { return (21 - (board.num_actions() as i8) / 2, 0); }
+    pub fn best_score(&mut self, board: Board) -> Result<(i8, SearchStats), Cancelled> {
+        self.opening_db = self.book.get();
+        self.budget = Budget { deadline: self.limits.time.map(|time| Instant::now() + time), nodes: self.limits.nodes };
+        self.search_score(board, None)
+    }
+
+    // `col` is the move that led to `board` when choosing a move, in which
+    // case progress is reported from the mover's point of view
+    fn search_score(&mut self, board: Board, col: Option<u8>) -> Result<(i8, SearchStats), Cancelled> {
+        if board.has_winning_action() { return Ok((21 - (board.num_actions() as i8) / 2, SearchStats::default())); }
 
-        self.visited = 0;
+        self.stats = SearchStats::default();
+        self.killers = [[NO_KILLER; 2]; 42];
+        self.history = [[0; 49]; 2];
+        self.transposition_table.next_age();
 
         let mut min = -(42 - board.num_actions() as i8) / 2;
         let mut max = (43 - board.num_actions() as i8) / 2;
 
+        let now = Instant::now();
+        self.search = SearchState { start: now, last_report: now, col, min, max, cancelled: false };
+        self.report();
+
         while min < max {
             let mut med = min + (max - min) / 2;
             if med <= 0 && min / 2 < med { med = min / 2; }
             else if med >= 0 && max / 2 > med { med = max / 2; }
 
+            self.stats.iterations += 1;
             let res = self.negamax(board, med, med + 1);
+            if self.search.cancelled {
+                return Err(Cancelled { min, max, col: None, scores: [None; WIDTH], nodes: self.stats.nodes, elapsed: self.search.start.elapsed() });
+            }
+
             if res <= med { max = res; }
             else { min = res; }
+
+            self.search.min = min;
+            self.search.max = max;
+            self.report();
         }
 
-        (min, self.visited)
+        self.stats.elapsed = self.search.start.elapsed();
+        Ok((min, self.stats))
     }
 
     fn negamax(&mut self, board: Board, mut alpha: i8, mut beta: i8) -> i8 {
-        self.visited += 1;
+        self.stats.nodes += 1;
+        if self.stats.nodes.is_multiple_of(POLL_INTERVAL) { self.poll(); }
+        if self.search.cancelled { return 0; }
 
         let actions_mask = board.get_non_losing_actions();
         if actions_mask == 0 { return -(42 - board.num_actions() as i8) / 2; }
 
         if board.num_actions() >= 40 { return 0; }
 
-        let mut min: i8 = -(40 - board.num_actions() as i8) / 2;
+        let min: i8 = -(40 - board.num_actions() as i8) / 2;
         if alpha < min {
             alpha = min;
             if alpha >= beta { return alpha; }
         }
 
-        let mut max: i8 = (41 - board.num_actions() as i8) / 2;
+        let max: i8 = (41 - board.num_actions() as i8) / 2;
         if beta > max {
             beta = max;
             if alpha >= beta { return beta; }
         }
 
+        if self.pruning.endgame && board.num_actions() >= 42 - ENDGAME_TILES {
+            if alpha < 0 && !board.can_connect_four(board.side_to_move().opponent()) {
+                alpha = 0;
+                if alpha >= beta { return alpha; }
+            }
+            if beta > 0 && !board.can_connect_four(board.side_to_move()) {
+                beta = 0;
+                if alpha >= beta { return beta; }
+            }
+        }
+
         let hash = board.hash();
-        if let Some(score) = self.transposition_table.get(hash) {
-            if score > MAX_SCORE - MIN_SCORE + 1 {
-                min = score + 2*MIN_SCORE - MAX_SCORE - 2;
-                if alpha < min {
-                    alpha = min;
-                    if alpha >= beta { return alpha; }
+        let mut tt_move = None;
+        self.stats.tt_probes += 1;
+        if let Some(entry) = self.transposition_table.get(hash) {
+            self.stats.tt_hits += 1;
+            tt_move = entry.best_col();
+            if entry.bound() != Bound::Upper && alpha < entry.score() {
+                alpha = entry.score();
+                if alpha >= beta {
+                    self.stats.tt_cutoffs += 1;
+                    return alpha;
                 }
-            } else {
-                max = score + MIN_SCORE - 1;
-                if beta > max {
-                    beta = max;
-                    if alpha >= beta { return beta; }
+            }
+            if entry.bound() != Bound::Lower && beta > entry.score() {
+                beta = entry.score();
+                if alpha >= beta {
+                    self.stats.tt_cutoffs += 1;
+                    return beta;
                 }
             }
         }
 
+        if self.knowledge && beta > 0 && board.num_actions() & 1 == 0 && board.num_actions() <= KNOWLEDGE_PLIES && knowledge::prove(board).is_some() {
+            // a proof holds however the position is reached, so transpositions skip it
+            self.transposition_table.set(hash, 0, Bound::Upper, tt_move, (42 - board.num_actions()) as u8);
+            beta = 0;
+            if alpha >= beta { return beta; }
+        }
+
         if let Some(ref db) = self.opening_db {
             if let Some(score) = db.get(board.hash(), board.num_actions()) {
+                self.stats.book_hits += 1;
                 return score;
             }
         }
 
+        let depth = (42 - board.num_actions()) as u8;
+
+        // winning on the next move but one is the best the side to move can do
+        if self.pruning.double_threats {
+            for col in COLUMN_ORDER {
+                let action = Board::get_action(actions_mask, col as i32);
+                if action != 0 && board.is_double_threat_action(action) {
+                    self.transposition_table.set(hash, max, Bound::Exact, Some(col as u8), depth);
+                    return max;
+                }
+            }
+        }
+
+        // deep enough in the tree the lookups cost more than the nodes they save
+        if self.pruning.etc && board.num_actions() < 42 - ENDGAME_TILES {
+            for col in COLUMN_ORDER {
+                let action = Board::get_action(actions_mask, col as i32);
+                if action == 0 { continue; }
+
+                self.stats.tt_probes += 1;
+                if let Some(entry) = self.transposition_table.get(board.make_action(action).hash()) {
+                    self.stats.tt_hits += 1;
+                    if entry.bound() != Bound::Lower && -entry.score() >= beta {
+                        self.stats.tt_cutoffs += 1;
+                        self.transposition_table.set(hash, -entry.score(), Bound::Lower, Some(col as u8), depth);
+                        return -entry.score();
+                    }
+                }
+            }
+        }
+
+        let mut best = None;
+        for (i, action) in self.order_actions(board, actions_mask, tt_move).enumerate() {
+            let child = board.make_action(action);
+            let score = -self.negamax(child, -beta, -alpha);
+
+            // the score of an interrupted search means nothing, keep it out of the table
+            if self.search.cancelled { return 0; }
+
+            if score >= beta {
+                self.stats.cutoff_index[i] += 1;
+                self.transposition_table.set(hash, score, Bound::Lower, Some(column(action)), depth);
+                self.record_cutoff(board, action);
+                return score;
+            }
+            if score > alpha {
+                alpha = score;
+                best = Some(action);
+            }
+        }
+
+        // a move scoring within the window scores exactly that
+        match best {
+            Some(action) => self.transposition_table.set(hash, alpha, Bound::Exact, Some(column(action)), depth),
+            None => self.transposition_table.set(hash, alpha, Bound::Upper, None, depth),
+        }
+        alpha
+    }
+
+    // remembers what caused a cutoff for the selected move ordering
+    fn record_cutoff(&mut self, board: Board, action: u64) {
+        let col = column(action);
+        let ply = board.num_actions();
+        if self.ordering.killers && self.killers[ply][0] != col {
+            self.killers[ply] = [col, self.killers[ply][0]];
+        }
+
+        if self.ordering.history {
+            let depth = (42 - ply) as u32;
+            let count = &mut self.history[ply & 1][action.trailing_zeros() as usize];
+            *count = count.saturating_add(depth * depth);
+        }
+    }
+
+    // moves are tried by the table's best move, then the score of the move,
+    // then killers and lastly history, each only breaking ties of the former
+    fn order_actions(&self, board: Board, actions_mask: u64, tt_move: Option<u8>) -> ActionOrdering {
+        let tt_move = if self.ordering.tt_move { tt_move } else { None };
+        let killers = if self.ordering.killers { self.killers[board.num_actions()] } else { [NO_KILLER; 2] };
+
         let sym = board.is_symmetrical();
         let mut actions_ordered = ActionOrdering::new();
         for i in if sym {[0, 1, 2, 3].iter()} else {[6, 0, 5, 1, 4, 2, 3].iter()} {
             let action = Board::get_action(actions_mask, *i);
             if action != 0 {
-                actions_ordered.push(action, board.get_action_score(action));
+                let col = column(action);
+                let tt = (tt_move == Some(col)) as u64;
+                let killer = if killers[0] == col { 2 } else if killers[1] == col { 1 } else { 0 };
+                let history = if self.ordering.history { self.history[board.num_actions() & 1][action.trailing_zeros() as usize].min(u32::MAX >> 2) } else { 0 };
+                actions_ordered.push(action, tt << 63 | (self.action_score(board, action) as u64) << 32 | killer << 30 | history as u64);
             }
         }
+        actions_ordered
+    }
 
-        for action in actions_ordered {
-            let child = board.make_action(action);
-            let score = -self.negamax(child, -beta, -alpha);
+    #[cfg(not(feature = "nn"))]
+    fn action_score(&self, board: Board, action: u64) -> u32 {
+        board.get_action_score(action)
+    }
 
-            if score >= beta {
-                self.transposition_table.set(hash, score + MAX_SCORE - 2*MIN_SCORE + 2);
-                return score; 
-            }
+    // the network's score of the move decides, in steps of 1/256, and the
+    // threats it makes break ties
+    #[cfg(feature = "nn")]
+    fn action_score(&self, board: Board, action: u64) -> u32 {
+        let threats = board.get_action_score(action);
+        match self.evaluator {
+            Some((ref network, max_ply)) if board.num_actions() <= max_ply => {
+                let score = -network.evaluate(&board.make_action(action));
+                (((score.clamp(-32.0, 32.0) + 32.0) * 256.0) as u32) << 8 | threats.min(255)
+            },
+            _ => threats,
+        }
+    }
+
+    /// Estimates the score of `board` by searching `depth` moves ahead and
+    /// scoring the positions there with the evaluator, or as draws without
+    /// one. Wins and losses within the horizon are exact, and no score ends
+    /// up in the transposition table.
+    #[cfg(feature = "nn")]
+    pub fn estimate(&mut self, board: Board, depth: usize) -> Result<(i8, SearchStats), Cancelled> {
+        self.stats = SearchStats::default();
+        self.killers = [[NO_KILLER; 2]; 42];
+        self.history = [[0; 49]; 2];
+        self.budget = Budget { deadline: self.limits.time.map(|time| Instant::now() + time), nodes: self.limits.nodes };
+        let min = -(42 - board.num_actions() as i8) / 2;
+        let max = (43 - board.num_actions() as i8) / 2;
+        let now = Instant::now();
+        self.search = SearchState { start: now, last_report: now, col: None, min, max, cancelled: false };
+
+        let score = if board.has_winning_action() { max } else { self.negamax_depth(board, depth, min, max) };
+        if self.search.cancelled {
+            return Err(Cancelled { min, max, col: None, scores: [None; WIDTH], nodes: self.stats.nodes, elapsed: self.search.start.elapsed() });
+        }
+
+        self.stats.elapsed = self.search.start.elapsed();
+        Ok((score, self.stats))
+    }
+
+    // `negamax` cut off `depth` moves ahead, where the side to move has no
+    // winning move either
+    #[cfg(feature = "nn")]
+    fn negamax_depth(&mut self, board: Board, depth: usize, mut alpha: i8, beta: i8) -> i8 {
+        self.stats.nodes += 1;
+        if self.stats.nodes.is_multiple_of(POLL_INTERVAL) { self.poll(); }
+        if self.search.cancelled { return 0; }
+
+        let actions_mask = board.get_non_losing_actions();
+        if actions_mask == 0 { return -(42 - board.num_actions() as i8) / 2; }
+
+        if board.num_actions() >= 40 { return 0; }
+
+        if depth == 0 {
+            let score = self.evaluator.as_ref().map_or(0.0, |(network, _)| network.evaluate(&board));
+            return (score.round() as i8).clamp(-(40 - board.num_actions() as i8) / 2, (41 - board.num_actions() as i8) / 2);
+        }
+
+        let tt_move = self.transposition_table.get(board.hash()).and_then(|entry| entry.best_col());
+        for action in self.order_actions(board, actions_mask, tt_move) {
+            let score = -self.negamax_depth(board.make_action(action), depth - 1, -beta, -alpha);
+            if self.search.cancelled { return 0; }
+
+            if score >= beta { return score; }
             if score > alpha { alpha = score; }
         }
 
-        self.transposition_table.set(hash, alpha - MIN_SCORE + 1);
         alpha
     }
+
+    fn poll(&mut self) {
+        let out_of_budget = self.budget.nodes.is_some_and(|nodes| self.stats.nodes >= nodes)
+            || self.budget.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if out_of_budget || self.cancel_token.as_ref().is_some_and(|token| token.is_cancelled()) {
+            self.search.cancelled = true;
+            return;
+        }
+
+        if self.observer.is_some() && self.search.last_report.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        let observer = match self.observer {
+            Some(ref observer) => observer,
+            None => return,
+        };
+
+        self.search.last_report = Instant::now();
+
+        // bounds of a reply are reported as bounds of the move that led to it
+        let (min, max) = match self.search.col {
+            Some(_) => (-self.search.max, -self.search.min),
+            None => (self.search.min, self.search.max),
+        };
+
+        observer.on_progress(&Progress {
+            col: self.search.col,
+            min,
+            max,
+            nodes: self.stats.nodes,
+            elapsed: self.search.start.elapsed(),
+        });
+    }
+}
+
+// the column of a move
+fn column(action: u64) -> u8 {
+    (action.trailing_zeros() / 7) as u8
+}
+
+/// The column with the highest score, preferring central columns on ties.
+pub fn best_column(scores: &[Option<i8>; WIDTH]) -> Option<(u8, i8)> {
+    let mut best: Option<(u8, i8)> = None;
+    for i in COLUMN_ORDER {
+        if let Some(score) = scores[i] {
+            if best.is_none_or(|(_, best_score)| score > best_score) { best = Some((i as u8, score)); }
+        }
+    }
+    best
+}
+
+/// The best move known when a search choosing one stopped early: a win, the
+/// best column proven, or else the most central one that doesn't hand the
+/// opponent a win.
+pub fn best_so_far(board: &Board, cancelled: &Cancelled) -> u8 {
+    let non_losing = board.get_non_losing_actions();
+    COLUMN_ORDER.into_iter().find(|&col| board.is_winning_col(col)).map(|col| col as u8)
+        .or(cancelled.col)
+        .or_else(|| COLUMN_ORDER.into_iter().find(|&col| board.is_valid_action(non_losing, col)).map(|col| col as u8))
+        .or_else(|| COLUMN_ORDER.into_iter().find(|&col| board.is_valid_col(col)).map(|col| col as u8))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::board::{GameStatus, Player};
+
+    #[test]
+    fn test_progress() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+
+        let mut agent = Agent::new(None);
+        agent.set_observer(Some(Arc::new(move |progress: &Progress| sink.lock().unwrap().push(*progress))));
+
+        let board = Board::from_position("2252576253462244111563365343671351441").unwrap();
+        let (score, stats) = agent.best_score(board).unwrap();
+        assert_eq!(score, -1);
+
+        // bounds only ever narrow, ending on the score
+        let events = events.lock().unwrap();
+        assert!(events.len() as u32 > stats.iterations);
+        assert!(events.windows(2).all(|w| w[1].min >= w[0].min && w[1].max <= w[0].max));
+        assert_eq!((events.last().unwrap().min, events.last().unwrap().max), (-1, -1));
+    }
+
+    #[test]
+    fn test_column_scores() {
+        let columns = Arc::new(Mutex::new(Vec::new()));
+        let sink = columns.clone();
+
+        struct Columns(Arc<Mutex<Vec<(u8, i8)>>>);
+        impl SearchObserver for Columns {
+            fn on_progress(&self, _progress: &Progress) {}
+            fn on_column(&self, col: u8, score: i8) { self.0.lock().unwrap().push((col, score)); }
+        }
+
+        let mut agent = Agent::new(None);
+        agent.set_observer(Some(Arc::new(Columns(sink))));
+
+        // x threatens to complete its bottom row in two columns
+        let board = Board::from_position("4455662").unwrap();
+        let (scores, _) = agent.column_scores(board).unwrap();
+        assert!(scores.iter().all(|score| score.is_some()));
+        let (col, score, _) = agent.best_col(board).unwrap();
+        assert_eq!(Some(score), scores[col as usize]);
+        assert_eq!(scores.iter().flatten().max(), Some(&score));
+
+        let columns = columns.lock().unwrap();
+        assert_eq!(columns.len(), 14);
+        assert!(columns[..7].iter().all(|&(col, score)| scores[col as usize] == Some(score)));
+        drop(columns);
+
+        // o cannot stop x, however it plays
+        agent.set_observer(None);
+        let mut end = board;
+        for col in agent.principal_variation(board, SearchLimits::default()).unwrap().0 { end.play_col(col as usize); }
+        assert!(matches!(end.status(), GameStatus::Win { player: Player::First, .. }));
+        assert_eq!(agent.principal_variation(Board::from_position("445566").unwrap(), SearchLimits::default()).unwrap().0, vec![2]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        token.cancel();
+
+        let mut agent = Agent::new(None);
+        agent.set_cancel_token(Some(token));
+
+        // a deep search notices the token at its first poll
+        let board = Board::from_position("44").unwrap();
+        let cancelled = agent.best_score(board).unwrap_err();
+        assert!(cancelled.min < cancelled.max);
+        assert_eq!(cancelled.nodes, POLL_INTERVAL);
+
+        let cancelled = agent.best_col(board).unwrap_err();
+        assert_eq!(cancelled.col, None);
+
+        agent.set_cancel_token(None);
+        assert!(agent.best_score(Board::from_position("2252576253462244111563365343671351441").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let lines = std::fs::read_to_string("benchmarks/Test_L2_R1").unwrap();
+        for ordering in ["tt", "killers", "history", "all"] {
+            let mut agent = Agent::new(None).with_table_size(0).with_ordering(ordering.parse().unwrap());
+            let mut cutoffs = [0; WIDTH];
+            for line in lines.lines().take(20) {
+                let (position, score) = line.split_once(' ').unwrap();
+                let (actual, stats) = agent.best_score(Board::from_position(position).unwrap()).unwrap();
+                assert_eq!(actual, score.parse::<i8>().unwrap(), "{} with {} ordering", position, ordering);
+                for (total, count) in cutoffs.iter_mut().zip(stats.cutoff_index) { *total += count; }
+            }
+            assert!(cutoffs[0] > 0);
+        }
+
+        // the root's best move is stored from the search proving its score
+        let board = Board::from_position("5554224333234511764415115").unwrap();
+        let mut agent = Agent::new(None).with_table_size(0).with_ordering(MoveOrdering { tt_move: true, ..Default::default() });
+        let (score, _) = agent.best_score(board).unwrap();
+        let col = agent.transposition_table.get(board.hash()).and_then(|entry| entry.best_col()).unwrap();
+        let mut child = board;
+        child.play_col(col as usize);
+        assert_eq!(-agent.best_score(child).unwrap().0, score);
+    }
+
+    #[test]
+    fn test_pruning() {
+        assert_eq!("all".parse::<Pruning>().unwrap().to_string(), "etc,threats,endgame");
+        assert_eq!("endgame,etc".parse(), Ok(Pruning { etc: true, double_threats: false, endgame: true }));
+        assert!("etc,nmp".parse::<Pruning>().is_err());
+
+        // pruning changes nodes but not scores
+        let lines = std::fs::read_to_string("benchmarks/Test_L2_R1").unwrap();
+        for pruning in ["etc", "threats", "endgame", "all"] {
+            let mut agent = Agent::new(None).with_table_size(0).with_pruning(pruning.parse().unwrap());
+            for line in lines.lines().take(20) {
+                let (position, score) = line.split_once(' ').unwrap();
+                assert_eq!(agent.best_score(Board::from_position(position).unwrap()).unwrap().0, score.parse::<i8>().unwrap(), "{} with {} pruning", position, pruning);
+            }
+        }
+
+        // neither player can complete a line with the tiles left
+        let board = Board::from_grid(".x.ox../.oxoxo./xxoxoxx/oxoxoxo/oxooxoo/xoxoxox x").unwrap();
+        let (score, stats) = Agent::new(None).with_table_size(0).best_score(board).unwrap();
+        let mut agent = Agent::new(None).with_table_size(0).with_pruning(Pruning { endgame: true, ..Default::default() });
+        let (pruned_score, pruned_stats) = agent.best_score(board).unwrap();
+        assert_eq!((score, pruned_score), (0, 0));
+        assert_eq!(pruned_stats.nodes, pruned_stats.iterations as u64);
+        assert!(pruned_stats.nodes < stats.nodes);
+    }
+
+    #[cfg(feature = "nn")]
+    #[test]
+    fn test_evaluator() {
+        use crate::nn::{Layer, Network, CELLS, INPUTS};
+
+        // counts central tiles, the side to move's up and the opponent's down
+        let weights = (0..INPUTS).map(|input| {
+            let centrality = 4.0 - (input % CELLS / 6).abs_diff(3) as f32;
+            if input < CELLS { centrality } else { -centrality }
+        }).collect();
+        let network = Arc::new(Network::new(vec![Layer { weights: vec![weights], biases: vec![0.0] }]).unwrap());
+
+        // ordering by the network changes nodes but not scores
+        let mut agent = Agent::new(None);
+        let mut ordered = Agent::new(None).with_evaluator(Some(network.clone()), 42);
+        for line in std::fs::read_to_string("benchmarks/Test_L3_R1").unwrap().lines().take(20) {
+            let board = Board::from_position(line.split(' ').next().unwrap()).unwrap();
+            assert_eq!(ordered.best_score(board).unwrap().0, agent.best_score(board).unwrap().0);
+        }
+
+        // the network scores the horizon, while searches reaching the end are exact
+        assert_eq!(ordered.estimate(Board::from_position("41").unwrap(), 0).unwrap().0, 3);
+        assert_eq!(agent.estimate(Board::from_position("41").unwrap(), 0).unwrap().0, 0);
+        assert_eq!(ordered.estimate(Board::from_position("4455662").unwrap(), 0).unwrap().0, -17);
+        let (score, stats) = ordered.estimate(Board::from_position("2252576253462244111563365343671351441").unwrap(), 5).unwrap();
+        assert_eq!(score, -1);
+        assert!(stats.nodes > 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut agent = Agent::new(None);
+        agent.set_limits(SearchLimits { nodes: Some(3 * POLL_INTERVAL), time: None });
+
+        // the budget covers every column together, stopping within a poll of it
+        let cancelled = agent.column_scores(Board::new()).unwrap_err();
+        assert!(cancelled.nodes >= 3 * POLL_INTERVAL && cancelled.nodes <= 4 * POLL_INTERVAL);
+        assert!(cancelled.min < cancelled.max);
+
+        agent.set_limits(SearchLimits { nodes: None, time: Some(Duration::from_millis(50)) });
+        let cancelled = agent.best_score(Board::from_position("44").unwrap()).unwrap_err();
+        assert!(cancelled.elapsed >= Duration::from_millis(50) && cancelled.elapsed < Duration::from_secs(5));
+
+        // a budget the search fits in changes nothing
+        agent.set_limits(SearchLimits { nodes: Some(1_000_000), time: None });
+        let board = Board::from_position("4455662").unwrap();
+        let (scores, _) = agent.column_scores(board).unwrap();
+        agent.set_limits(SearchLimits::default());
+        assert_eq!(agent.column_scores(board).unwrap().0, scores);
+
+        // each move of the line fits in the budget, but not all of them together
+        let board = Board::from_position("434434443353").unwrap();
+        let limits = SearchLimits { nodes: Some(20_000), time: None };
+        assert!(Agent::new(None).principal_variation(board, limits).is_err());
+        assert!(agent.principal_variation(board, SearchLimits::default()).is_ok());
+        assert_eq!(agent.limits(), SearchLimits::default());
+    }
 }
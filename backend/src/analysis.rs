@@ -0,0 +1,113 @@
+use serde::Serialize;
+
+use crate::board::{mask_cells, mask_cols, Board, Player};
+use crate::knowledge;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Threats {
+    /// Empty tiles where the player would complete a four in a row.
+    pub cells: Vec<(usize, usize)>,
+    /// Threats on rows 1, 3 and 5, counting from 1 at the bottom.
+    pub odd: Vec<(usize, usize)>,
+    /// Threats on rows 2, 4 and 6.
+    pub even: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Analysis {
+    pub side_to_move: Player,
+    pub first: Threats,
+    pub second: Threats,
+    /// Columns where the side to move wins immediately.
+    pub winning_moves: Vec<usize>,
+    /// Columns the side to move must play to stop an immediate loss.
+    pub forced_moves: Vec<usize>,
+    /// Columns directly below an opponent threat, which hand the opponent a win.
+    pub losing_moves: Vec<usize>,
+    /// The player expected to profit once the board fills up and someone is
+    /// forced to play below a threat.
+    pub zugzwang: Player,
+    /// Rules showing the second player cannot lose, if they can be found.
+    pub proof: Option<Vec<String>>,
+}
+
+impl Analysis {
+    /// Returns `None` for finished games, where there is nothing to explain.
+    pub fn new(board: Board) -> Option<Analysis> {
+        if board.status().is_over() { return None; }
+
+        let player = board.side_to_move();
+        let playable = board.playable_tile_mask();
+        let own_threats = board.threat_mask(player);
+        let enemy_threats = board.threat_mask(player.opponent());
+
+        Some(Analysis {
+            side_to_move: player,
+            first: threats(board, Player::First),
+            second: threats(board, Player::Second),
+            winning_moves: mask_cols(playable & own_threats),
+            forced_moves: mask_cols(playable & enemy_threats),
+            losing_moves: mask_cols(playable & (enemy_threats >> 1)),
+            zugzwang: zugzwang(board),
+            proof: knowledge::prove(board).map(|proof| proof.rules.iter().map(|rule| rule.to_string()).collect()),
+        })
+    }
+}
+
+fn threats(board: Board, player: Player) -> Threats {
+    Threats {
+        cells: mask_cells(board.threat_mask(player)),
+        odd: mask_cells(board.odd_threat_mask(player)),
+        even: mask_cells(board.even_threat_mask(player)),
+    }
+}
+
+// Rule of thumb: when the board fills up column by column, the second player
+// can always answer on the same column and claims every even square, so they
+// control zugzwang unless the first player has an odd threat with no second
+// player threat beneath it in the same column.
+fn zugzwang(board: Board) -> Player {
+    let first_odd = board.odd_threat_mask(Player::First);
+    let second = board.threat_mask(Player::Second);
+
+    for (col, row) in mask_cells(first_odd) {
+        let below = ((1u64 << row) - 1) << (col * 7);
+        if second & below == 0 { return Player::First; }
+    }
+
+    Player::Second
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analysis() {
+        let analysis = Analysis::new(Board::new()).unwrap();
+        assert_eq!(analysis.side_to_move, Player::First);
+        assert!(analysis.first.cells.is_empty());
+        assert!(analysis.forced_moves.is_empty());
+        assert_eq!(analysis.zugzwang, Player::Second);
+
+        // x has an open three on the bottom row, o must block one end and still loses
+        let analysis = Analysis::new(Board::from_position("44556").unwrap()).unwrap();
+        assert_eq!(analysis.side_to_move, Player::Second);
+        assert_eq!(analysis.first.odd, vec![(2, 0), (6, 0)]);
+        assert_eq!(analysis.forced_moves, vec![2, 6]);
+        assert!(analysis.winning_moves.is_empty());
+        assert_eq!(analysis.zugzwang, Player::First);
+
+        let analysis = Analysis::new(Board::from_position("445566").unwrap()).unwrap();
+        assert_eq!(analysis.winning_moves, vec![2, 6]);
+        assert_eq!(analysis.second.even, vec![(2, 1), (6, 1)]);
+
+        // o threatens the second row at both ends, so x must not play underneath
+        let analysis = Analysis::new(Board::from_grid("......./......./......./......./..ooo../x.xox.x x").unwrap()).unwrap();
+        assert_eq!(analysis.second.even, vec![(1, 1), (5, 1)]);
+        assert_eq!(analysis.losing_moves, vec![1, 5]);
+        assert!(analysis.forced_moves.is_empty());
+
+        assert!(Analysis::new(Board::from_position("4455667").unwrap()).is_none());
+    }
+}
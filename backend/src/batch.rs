@@ -0,0 +1,380 @@
+use std::io::{Error, ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::agent::Agent;
+use crate::board::{Board, WIDTH};
+use crate::opening_db::{OpeningDatabase, SharedBook};
+use crate::progress::{CancellationToken, SearchLimits};
+use crate::stats::serialize_micros;
+use crate::transposition::TABLE_SIZE;
+
+/// A position to solve, with the score it should have when validating.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BatchPosition {
+    /// Columns played from the start, numbered from 1.
+    pub position: String,
+    #[serde(default)]
+    pub expected: Option<i8>,
+}
+
+// a JSON array may hold bare position strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonPosition {
+    Moves(String),
+    Position(BatchPosition),
+}
+
+/// Reads a JSON array of positions, either strings or objects like
+/// `{"position": "4455", "expected": -2}`, or else one position per line
+/// followed by its expected score if known, as in the benchmark files.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_positions(input: &str) -> std::io::Result<Vec<BatchPosition>> {
+    if input.trim_start().starts_with('[') {
+        let positions: Vec<JsonPosition> = serde_json::from_str(input).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        return Ok(positions.into_iter().map(|position| match position {
+            JsonPosition::Moves(position) => BatchPosition { position, expected: None },
+            JsonPosition::Position(position) => position,
+        }).collect());
+    }
+
+    input.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let mut parts = line.split_whitespace();
+            let position = parts.next().unwrap().to_string();
+            let expected = match parts.next() {
+                Some(score) => Some(score.parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid score on line {}: {}", i + 1, score)))?),
+                None => None,
+            };
+            Ok(BatchPosition { position, expected })
+        })
+        .collect()
+}
+
+/// A solved position of a batch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BatchResult {
+    /// Where the position was in the batch, as results come in the order
+    /// they are solved.
+    pub index: usize,
+    pub position: String,
+    /// The side to move's score, None if the position was not solved.
+    pub score: Option<i8>,
+    /// Every column reaching the score, numbered from 0.
+    pub best_moves: Vec<u8>,
+    pub nodes: u64,
+    #[serde(rename = "time_us", serialize_with = "serialize_micros")]
+    pub elapsed: Duration,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    fn unsolved(index: usize, position: &BatchPosition) -> BatchResult {
+        BatchResult {
+            index,
+            position: position.position.clone(),
+            score: None,
+            best_moves: Vec::new(),
+            nodes: 0,
+            elapsed: Duration::ZERO,
+            expected: position.expected,
+            error: None,
+        }
+    }
+
+    /// The position was solved to a score other than the expected one.
+    pub fn is_mismatch(&self) -> bool {
+        self.expected.is_some() && self.score.is_some() && self.score != self.expected
+    }
+}
+
+#[derive(Clone)]
+pub struct BatchOptions {
+    /// Shared by every thread.
+    pub book: Option<Arc<OpeningDatabase>>,
+    /// Transposition table entries of each thread's agent.
+    pub table_size: usize,
+    /// Threads solving in parallel, 0 for one per core.
+    pub threads: usize,
+    /// Budget of each position.
+    pub limits: SearchLimits,
+    /// Budget of the whole batch. Each position gets at most what is left of
+    /// it, and once it is spent the positions not yet started are left
+    /// unsolved without being searched.
+    pub budget: SearchLimits,
+    /// Stops the batch, leaving the positions not yet started unsolved.
+    pub cancel_token: Option<CancellationToken>,
+}
+
+impl BatchOptions {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel_token.as_ref().is_some_and(|token| token.is_cancelled())
+    }
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            book: None,
+            table_size: TABLE_SIZE,
+            threads: 0,
+            limits: SearchLimits::default(),
+            budget: SearchLimits::default(),
+            cancel_token: None,
+        }
+    }
+}
+
+/// Solves every column of each position in parallel and hands the results
+/// to `sink` on the calling thread as they are solved. Each thread keeps one
+/// agent, whose transposition table carries over between its positions.
+pub fn solve_batch(positions: &[BatchPosition], options: &BatchOptions, sink: impl FnMut(BatchResult)) {
+    AgentPool::new(options).solve(positions, options, sink);
+}
+
+/// A thread pool whose threads each keep their own agent, which can be kept
+/// to solve one batch after another.
+pub struct AgentPool {
+    pool: ThreadPool,
+    agents: Vec<Mutex<Agent>>,
+}
+
+impl AgentPool {
+    /// Starts `options.threads` threads, each with an agent using the book
+    /// and table size of `options`.
+    pub fn new(options: &BatchOptions) -> AgentPool {
+        let pool = ThreadPoolBuilder::new().num_threads(options.threads).build().unwrap();
+        let agents = (0..pool.current_num_threads()).map(|_| {
+            let mut agent = Agent::new(options.book.clone()).with_table_size(options.table_size);
+            agent.set_limits(options.limits);
+            agent.set_cancel_token(options.cancel_token.clone());
+            Mutex::new(agent)
+        }).collect();
+
+        AgentPool { pool, agents }
+    }
+
+    /// Has every agent use `book`, following it as it is swapped.
+    pub fn with_book(self, book: SharedBook) -> Self {
+        let agents = self.agents.into_iter().map(|agent| Mutex::new(agent.into_inner().unwrap().with_book(book.clone()))).collect();
+        AgentPool { agents, ..self }
+    }
+
+    /// Like `solve_batch`, with the limits and cancellation token of `options`.
+    pub fn solve(&self, positions: &[BatchPosition], options: &BatchOptions, mut sink: impl FnMut(BatchResult)) {
+        for agent in self.agents.iter() { agent.lock().unwrap().set_cancel_token(options.cancel_token.clone()); }
+
+        let start = Instant::now();
+        let nodes = AtomicU64::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| self.install(|| {
+                positions.par_iter().enumerate().for_each_with(sender, |sender, (index, position)| {
+                    if options.is_cancelled() { return; }
+
+                    // each position gets what is left of the batch's budget at most,
+                    // and once it is spent the rest are not searched at all
+                    let limits = options.limits.min(&options.budget.remaining(nodes.load(Ordering::Relaxed), start.elapsed()));
+                    let result = if limits.is_spent() {
+                        BatchResult { error: Some("batch budget spent".to_string()), ..BatchResult::unsolved(index, position) }
+                    } else {
+                        let mut agent = self.agent();
+                        agent.set_limits(limits);
+                        solve_position(&mut agent, index, position)
+                    };
+                    nodes.fetch_add(result.nodes, Ordering::Relaxed);
+                    let _ = sender.send(result);
+                });
+            }));
+
+            for result in receiver { sink(result); }
+        });
+    }
+
+    pub(crate) fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(f)
+    }
+
+    // the agent of the pool thread calling
+    pub(crate) fn agent(&self) -> MutexGuard<'_, Agent> {
+        self.agents[rayon::current_thread_index().unwrap()].lock().unwrap()
+    }
+}
+
+fn solve_position(agent: &mut Agent, index: usize, position: &BatchPosition) -> BatchResult {
+    let start = Instant::now();
+    let mut result = BatchResult::unsolved(index, position);
+
+    match Board::from_position(&position.position) {
+        Ok(board) if board.status().is_over() => result.error = Some("game is over".to_string()),
+        Ok(board) => match agent.column_scores(board) {
+            Ok((scores, stats)) => {
+                let best = scores.iter().flatten().max().copied();
+                result.score = best;
+                result.best_moves = (0..WIDTH).filter(|&col| scores[col] == best).map(|col| col as u8).collect();
+                result.nodes = stats.nodes;
+            },
+            Err(cancelled) => {
+                result.nodes = cancelled.nodes;
+                result.error = Some("search stopped before solving".to_string());
+            },
+        },
+        Err(e) => result.error = Some(e.to_string()),
+    }
+
+    result.elapsed = start.elapsed();
+    result
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BatchFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(BatchFormat::Csv),
+            "jsonl" => Ok(BatchFormat::Jsonl),
+            _ => Err(format!("unknown format {}, expected csv or jsonl", s)),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "index,position,score,best_moves,nodes,time_us,expected,error";
+
+impl BatchFormat {
+    /// Writes what comes before the first result, the header of a CSV.
+    pub fn write_header(self, out: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            BatchFormat::Csv => writeln!(out, "{}", CSV_HEADER),
+            BatchFormat::Jsonl => Ok(()),
+        }
+    }
+
+    /// Writes one result per line. In CSV the best moves are separated by
+    /// spaces and missing values are left empty.
+    pub fn write(self, out: &mut impl Write, result: &BatchResult) -> std::io::Result<()> {
+        match self {
+            BatchFormat::Jsonl => writeln!(out, "{}", serde_json::to_string(result).unwrap()),
+            BatchFormat::Csv => {
+                let optional = |value: Option<i8>| value.map_or(String::new(), |value| value.to_string());
+                let best_moves: Vec<String> = result.best_moves.iter().map(|col| col.to_string()).collect();
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{}",
+                    result.index,
+                    csv_field(&result.position),
+                    optional(result.score),
+                    best_moves.join(" "),
+                    result.nodes,
+                    result.elapsed.as_micros(),
+                    optional(result.expected),
+                    csv_field(result.error.as_deref().unwrap_or("")),
+                )
+            },
+        }
+    }
+}
+
+// quotes a field holding a separator or quote
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.to_string() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_positions() {
+        let positions = parse_positions("# position score\n4455 -2\n\n3\n").unwrap();
+        assert_eq!(positions, vec![
+            BatchPosition { position: "4455".to_string(), expected: Some(-2) },
+            BatchPosition { position: "3".to_string(), expected: None },
+        ]);
+
+        let positions = parse_positions(r#"["44", {"position": "45", "expected": 1}]"#).unwrap();
+        assert_eq!((positions[0].expected, positions[1].position.as_str()), (None, "45"));
+
+        assert!(parse_positions("4455 win").is_err());
+        assert!(parse_positions("[44]").is_err());
+    }
+
+    #[test]
+    fn test_solve_batch() {
+        let mut positions = parse_positions(&std::fs::read_to_string("benchmarks/Test_L3_R1").unwrap()).unwrap();
+        positions.truncate(20);
+        positions.push(BatchPosition { position: "445566".to_string(), expected: Some(0) });
+        positions.push(BatchPosition { position: "4455667".to_string(), expected: None });
+        positions.push(BatchPosition { position: "8".to_string(), expected: None });
+
+        let mut results = Vec::new();
+        solve_batch(&positions, &BatchOptions { threads: 2, table_size: 0, ..Default::default() }, |result| results.push(result));
+        results.sort_by_key(|result| result.index);
+
+        assert_eq!(results.len(), 23);
+        assert!(results[..20].iter().all(|result| result.error.is_none() && !result.is_mismatch()));
+        assert_eq!((results[20].score, &results[20].best_moves), (Some(18), &vec![2, 6]));
+        assert!(results[20].is_mismatch());
+        assert_eq!(results[21].error.as_deref(), Some("game is over"));
+        assert!(results[22].score.is_none() && results[22].error.is_some());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut solved = 0;
+        solve_batch(&positions, &BatchOptions { threads: 2, table_size: 0, cancel_token: Some(token), ..Default::default() }, |_| solved += 1);
+        assert_eq!(solved, 0);
+
+        // the batch's budget caps positions without a budget of their own
+        let positions = vec![BatchPosition { position: "44".to_string(), expected: None }; 3];
+        let budget = SearchLimits { nodes: Some(5000), time: None };
+        let mut results = Vec::new();
+        solve_batch(&positions, &BatchOptions { threads: 1, table_size: 0, budget, ..Default::default() }, |result| results.push(result));
+        results.sort_by_key(|result| result.index);
+        assert_eq!(results[0].error.as_deref(), Some("search stopped before solving"));
+        // the positions after the first start with the budget spent, and are not searched
+        for result in &results[1..] {
+            assert_eq!((result.error.as_deref(), result.nodes), (Some("batch budget spent"), 0));
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let result = BatchResult {
+            index: 3,
+            position: "445566".to_string(),
+            score: Some(18),
+            best_moves: vec![2, 6],
+            nodes: 1,
+            elapsed: Duration::from_micros(12),
+            expected: None,
+            error: None,
+        };
+
+        let mut out = Vec::new();
+        BatchFormat::Csv.write_header(&mut out).unwrap();
+        BatchFormat::Csv.write(&mut out, &result).unwrap();
+        BatchFormat::Csv.write(&mut out, &BatchResult { error: Some("invalid character ','".to_string()), score: None, ..result.clone() }).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n3,445566,18,2 6,1,12,,\n3,445566,,2 6,1,12,,\"invalid character ','\"\n", CSV_HEADER));
+
+        let mut out = Vec::new();
+        BatchFormat::Jsonl.write(&mut out, &result).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"index\":3,\"position\":\"445566\",\"score\":18,\"best_moves\":[2,6],\"nodes\":1,\"time_us\":12}\n");
+        assert_eq!("tsv".parse::<BatchFormat>(), Err("unknown format tsv, expected csv or jsonl".to_string()));
+    }
+}
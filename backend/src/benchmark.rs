@@ -0,0 +1,220 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+
+use crate::board::Board;
+use crate::agent::Agent;
+use crate::opening_db::OpeningDatabase;
+use crate::stats::SearchStats;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+}
+
+impl Summary {
+    pub fn new(mut values: Vec<f64>) -> Summary {
+        if values.is_empty() { return Summary::default(); }
+
+        values.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+
+        Summary {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            median: percentile(0.5),
+            p95: percentile(0.95),
+        }
+    }
+}
+
+/// Results of solving every position of one benchmark file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchmarkReport {
+    pub name: String,
+    pub book: bool,
+    /// How the search was set up when not the default way, see `run_variant`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub variant: String,
+    pub positions: usize,
+    /// Positions whose score differs from the expected one.
+    pub mismatches: usize,
+    pub time_us: Summary,
+    pub nodes: Summary,
+    pub nodes_per_sec: f64,
+    pub tt_hit_rate: f64,
+    /// Share of beta cutoffs caused by the first move tried.
+    #[serde(default)]
+    pub first_cutoff_rate: f64,
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} ({}, {} positions, {} mismatches)", self.name, describe(self.book, &self.variant), self.positions, self.mismatches)?;
+        writeln!(f, "  time:  mean {}  median {}  p95 {}", format_time(self.time_us.mean), format_time(self.time_us.median), format_time(self.time_us.p95))?;
+        writeln!(f, "  nodes: mean {:.0}  median {:.0}  p95 {:.0}", self.nodes.mean, self.nodes.median, self.nodes.p95)?;
+        write!(f, "  {:.0} nodes/s  TT hit rate {:.1}%  first move cutoffs {:.1}%", self.nodes_per_sec, self.tt_hit_rate * 100.0, self.first_cutoff_rate * 100.0)
+    }
+}
+
+/// Solves each `<position> <score>` line of a benchmark file with a fresh
+/// agent, optionally stopping after `limit` positions.
+pub fn run_file(path: &str, db: Option<Arc<OpeningDatabase>>, limit: Option<usize>) -> std::io::Result<BenchmarkReport> {
+    run_variant(path, db, limit, "", |agent| agent)
+}
+
+/// Like `run_file`, with each agent set up by `configure` and the report
+/// named `variant` to tell it from the default search's.
+pub fn run_variant(path: &str, db: Option<Arc<OpeningDatabase>>, limit: Option<usize>, variant: &str, configure: impl Fn(Agent) -> Agent) -> std::io::Result<BenchmarkReport> {
+    let bench_file = std::fs::read_to_string(path)?;
+
+    let mut times = Vec::new();
+    let mut nodes = Vec::new();
+    let mut mismatches = 0;
+    let mut total = SearchStats::default();
+    for line in bench_file.lines().take(limit.unwrap_or(usize::MAX)) {
+        let (board, expected) = parse_line(line).ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("invalid benchmark line: {}", line)))?;
+
+        let mut agent = configure(Agent::new(db.clone()));
+        let start = std::time::Instant::now();
+        let (actual, stats) = agent.best_score(board).unwrap();
+        times.push(start.elapsed().as_secs_f64() * 1_000_000.0);
+        nodes.push(stats.nodes as f64);
+
+        total += stats;
+        if actual != expected { mismatches += 1; }
+    }
+
+    let total_time: f64 = times.iter().sum();
+    let total_nodes: f64 = nodes.iter().sum();
+    Ok(BenchmarkReport {
+        name: Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().to_string()),
+        book: db.is_some(),
+        variant: variant.to_string(),
+        positions: times.len(),
+        mismatches,
+        time_us: Summary::new(times),
+        nodes: Summary::new(nodes),
+        nodes_per_sec: if total_time > 0.0 { total_nodes / total_time * 1_000_000.0 } else { 0.0 },
+        tt_hit_rate: total.tt_hit_rate(),
+        first_cutoff_rate: total.first_cutoff_rate(),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Regression {
+    pub name: String,
+    pub book: bool,
+    pub variant: String,
+    pub metric: &'static str,
+    pub baseline: f64,
+    pub current: f64,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = if self.baseline > 0.0 { (self.current / self.baseline - 1.0) * 100.0 } else { f64::INFINITY };
+        write!(f, "{} ({}): {} {:.1} -> {:.1} (+{:.1}%)", self.name, describe(self.book, &self.variant), self.metric, self.baseline, self.current, change)
+    }
+}
+
+/// Flags reports that got slower or searched more nodes than the baseline
+/// report with the same name, book setting and variant by more than
+/// `tolerance` (0.1 for 10%), or that score more positions wrong.
+pub fn compare(current: &[BenchmarkReport], baseline: &[BenchmarkReport], tolerance: f64) -> Vec<Regression> {
+    let mut regressions = Vec::new();
+    for report in current.iter() {
+        let base = match baseline.iter().find(|b| b.name == report.name && b.book == report.book && b.variant == report.variant) {
+            Some(base) => base,
+            None => continue,
+        };
+
+        let metrics = [
+            ("mismatches", base.mismatches as f64, report.mismatches as f64, 0.0),
+            ("mean time (us)", base.time_us.mean, report.time_us.mean, tolerance),
+            ("mean nodes", base.nodes.mean, report.nodes.mean, tolerance),
+        ];
+
+        for (metric, baseline, current, tolerance) in metrics {
+            if current > baseline * (1.0 + tolerance) {
+                regressions.push(Regression { name: report.name.clone(), book: report.book, variant: report.variant.clone(), metric, baseline, current });
+            }
+        }
+    }
+
+    regressions
+}
+
+pub fn format_time(us: f64) -> String {
+    if us < 1_000.0 { format!("{:.2} us", us) }
+    else if us < 1_000_000.0 { format!("{:.2} ms", us / 1_000.0) }
+    else { format!("{:.2} s", us / 1_000_000.0) }
+}
+
+fn describe(book: bool, variant: &str) -> String {
+    let book = if book { "book" } else { "no book" };
+    if variant.is_empty() { book.to_string() } else { format!("{}, {}", book, variant) }
+}
+
+fn parse_line(line: &str) -> Option<(Board, i8)> {
+    let mut parts = line.split(' ');
+    let board = Board::from_position(parts.next()?).ok()?;
+    let score = parts.next()?.parse::<i8>().ok()?;
+    Some((board, score))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report(name: &str, mean_time: f64, mean_nodes: f64) -> BenchmarkReport {
+        BenchmarkReport {
+            name: name.to_string(),
+            book: false,
+            variant: String::new(),
+            positions: 1,
+            mismatches: 0,
+            time_us: Summary { mean: mean_time, median: mean_time, p95: mean_time },
+            nodes: Summary { mean: mean_nodes, median: mean_nodes, p95: mean_nodes },
+            nodes_per_sec: 0.0,
+            tt_hit_rate: 0.0,
+            first_cutoff_rate: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::new((1..=100).rev().map(|v| v as f64).collect());
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!(summary.median, 51.0);
+        assert_eq!(summary.p95, 95.0);
+        assert_eq!(Summary::new(Vec::new()), Summary::default());
+    }
+
+    #[test]
+    fn test_compare() {
+        let baseline = vec![report("a", 100.0, 1000.0), report("b", 100.0, 1000.0)];
+        let mut variant = report("a", 1.0, 5000.0);
+        variant.variant = "nn".to_string();
+        let current = vec![report("a", 105.0, 1000.0), report("b", 100.0, 1200.0), report("c", 1.0, 1.0), variant];
+
+        let regressions = compare(&current, &baseline, 0.1);
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].name, "b");
+        assert_eq!(regressions[0].metric, "mean nodes");
+        assert_eq!(regressions[0].to_string(), "b (no book): mean nodes 1000.0 -> 1200.0 (+20.0%)");
+    }
+
+    #[test]
+    fn test_run_file() {
+        let report = run_file("benchmarks/Test_L3_R1", None, Some(20)).unwrap();
+        assert_eq!(report.name, "Test_L3_R1");
+        assert_eq!(report.positions, 20);
+        assert_eq!(report.mismatches, 0);
+        assert!(report.nodes.mean > 0.0);
+        assert!(run_file("benchmarks/missing", None, None).is_err());
+    }
+}
@@ -1,12 +1,100 @@
-const WIDTH: usize = 7;
-const HEIGHT: usize = 6;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+pub const WIDTH: usize = 7;
+pub const HEIGHT: usize = 6;
 // const SIZE: usize = WIDTH * HEIGHT;
 
 const BOTTOM_ROW_MASK: u64 = 0b0000001000000100000010000001000000100000010000001;
-const PLAYABLE_AREA_MASK: u64 = 0b0111111011111101111110111111011111101111110111111;
+pub(crate) const PLAYABLE_AREA_MASK: u64 = 0b0111111011111101111110111111011111101111110111111;
+// rows 1, 3 and 5 when counting from 1 at the bottom
+pub(crate) const ODD_ROWS_MASK: u64 = 0b0010101001010100101010010101001010100101010010101;
 
 
-#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Player {
+    First,
+    Second,
+}
+
+impl Player {
+    pub fn opponent(self) -> Player {
+        match self {
+            Player::First => Player::Second,
+            Player::Second => Player::First,
+        }
+    }
+
+    pub(crate) fn symbol(self) -> char {
+        match self {
+            Player::First => 'x',
+            Player::Second => 'o',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GameStatus {
+    Ongoing,
+    /// `cells` is a mask of every tile belonging to a four in a row.
+    Win { player: Player, cells: u64 },
+    Draw,
+}
+
+impl GameStatus {
+    pub fn is_over(self) -> bool {
+        self != GameStatus::Ongoing
+    }
+
+    /// Winning tiles as `(col, row)` pairs, with row 0 at the bottom.
+    pub fn winning_cells(self) -> Vec<(usize, usize)> {
+        match self {
+            GameStatus::Win { cells, .. } => mask_cells(cells),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PositionError {
+    InvalidCharacter(char),
+    InvalidColumn(usize),
+    ColumnFull(usize),
+    RowCount(usize),
+    RowLength(usize),
+    MissingSideToMove,
+    FloatingTile(usize),
+    TileCount,
+    SideToMove,
+    InvalidMask,
+    AlreadyWon,
+}
+
+impl fmt::Display for PositionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PositionError::InvalidCharacter(c) => write!(f, "invalid character '{}'", c),
+            PositionError::InvalidColumn(col) => write!(f, "column {} is out of range", col + 1),
+            PositionError::ColumnFull(col) => write!(f, "column {} is full", col + 1),
+            PositionError::RowCount(n) => write!(f, "expected {} rows, found {}", HEIGHT, n),
+            PositionError::RowLength(n) => write!(f, "expected {} tiles per row, found {}", WIDTH, n),
+            PositionError::MissingSideToMove => write!(f, "missing side to move"),
+            PositionError::FloatingTile(col) => write!(f, "floating tile in column {}", col + 1),
+            PositionError::TileCount => write!(f, "tile counts do not alternate between players"),
+            PositionError::SideToMove => write!(f, "side to move does not match tile counts"),
+            PositionError::InvalidMask => write!(f, "masks do not describe a board"),
+            PositionError::AlreadyWon => write!(f, "position already contains four in a row"),
+        }
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub struct Board {
     player_mask: u64,
     tile_mask: u64,
@@ -14,6 +102,12 @@ pub struct Board {
 }
 
 
+impl Default for Board {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Board {
     pub fn new() -> Board {
         Board {
@@ -23,20 +117,179 @@ impl Board {
         }
     }
 
-    pub fn from_position(position: &str) -> Result<Board, ()> {
+    pub fn from_position(position: &str) -> Result<Board, PositionError> {
         let mut board = Board::new();
         for c in position.chars() {
-            if !c.is_digit(10) { return Err(()); }
+            let col = parse_col(c)?;
+            if board.status().is_over() { return Err(PositionError::AlreadyWon); }
+            if !board.is_valid_col(col) { return Err(PositionError::ColumnFull(col)); }
+
+            board.play_col(col);
+        }
+
+        Ok(board)
+    }
+
+    /// Finds a move sequence that reaches this board, the inverse of
+    /// `from_position`. Returns `None` if no legal game leads here.
+    pub fn to_position(self: &Board) -> Option<String> {
+        let mut moves = Vec::with_capacity(self.num_actions);
+        let mut dead_ends = HashSet::new();
+        if !unplay(self.player_mask, self.tile_mask, &mut moves, &mut dead_ends) { return None; }
+
+        Some(moves.iter().rev().map(|col| char::from(b'1' + *col as u8)).collect())
+    }
+
+    /// Parses a grid such as `......./......./......./......./...o.../...x... x`:
+    /// six rows from top to bottom separated by `/`, then the side to move.
+    /// `x` is the first player and `o` the second.
+    pub fn from_grid(grid: &str) -> Result<Board, PositionError> {
+        let mut parts = grid.split_whitespace();
+        let rows = parts.next().unwrap_or("").split('/').collect::<Vec<&str>>();
+        if rows.len() != HEIGHT { return Err(PositionError::RowCount(rows.len())); }
+
+        let mut first_mask = 0;
+        let mut second_mask = 0;
+        for (i, row) in rows.iter().enumerate() {
+            let r = HEIGHT - 1 - i;
+            if row.chars().count() != WIDTH { return Err(PositionError::RowLength(row.chars().count())); }
+
+            for (c, tile) in row.chars().enumerate() {
+                match tile {
+                    'x' => first_mask |= tile_bit(c, r),
+                    'o' => second_mask |= tile_bit(c, r),
+                    '.' => {},
+                    _ => return Err(PositionError::InvalidCharacter(tile)),
+                }
+            }
+        }
+
+        let to_move = match parts.next() {
+            Some("x") => Player::First,
+            Some("o") => Player::Second,
+            Some(s) => return Err(PositionError::InvalidCharacter(s.chars().next().unwrap())),
+            None => return Err(PositionError::MissingSideToMove),
+        };
+        if let Some(s) = parts.next() {
+            return Err(PositionError::InvalidCharacter(s.chars().next().unwrap()));
+        }
+
+        let (num_first, num_second) = (first_mask.count_ones(), second_mask.count_ones());
+        if num_first != num_second && num_first != num_second + 1 { return Err(PositionError::TileCount); }
+
+        let expected = if num_first == num_second { Player::First } else { Player::Second };
+        if to_move != expected { return Err(PositionError::SideToMove); }
+
+        let tile_mask = first_mask | second_mask;
+        match to_move {
+            Player::First => Board::from_masks(first_mask, tile_mask),
+            Player::Second => Board::from_masks(second_mask, tile_mask),
+        }
+    }
+
+    /// Builds a board from the raw bitboards, where `player_mask` holds the
+    /// tiles of the side to move and `tile_mask` every occupied tile.
+    pub fn from_masks(player_mask: u64, tile_mask: u64) -> Result<Board, PositionError> {
+        if tile_mask & !PLAYABLE_AREA_MASK != 0 || player_mask & !tile_mask != 0 {
+            return Err(PositionError::InvalidMask);
+        }
 
-            let col: u32 = c.to_digit(10).unwrap() - 1;
-            if col > 6 || !board.is_valid_col(col as usize) { return Err(()); } 
+        for col in 0..WIDTH {
+            let c = get_col(tile_mask, col);
+            if c & (c + 1) != 0 { return Err(PositionError::FloatingTile(col)); }
+        }
 
-            board.play_col(col as usize);
+        let num_actions = tile_mask.count_ones() as usize;
+        if player_mask.count_ones() as usize != num_actions / 2 { return Err(PositionError::TileCount); }
+
+        // like `from_position`, only the last move may complete four in a row:
+        // the side to move has none and taking back one of the opponent's top
+        // tiles leaves no four either
+        let last_mask = player_mask ^ tile_mask;
+        if has_four(player_mask) { return Err(PositionError::AlreadyWon); }
+        if has_four(last_mask) && !(0..WIDTH).any(|col| {
+            let top = (get_col(tile_mask, col) + 1) >> 1 << (col * 7);
+            last_mask & top != 0 && !has_four(last_mask ^ top)
+        }) {
+            return Err(PositionError::AlreadyWon);
         }
 
+        Ok(Board { player_mask, tile_mask, num_actions })
+    }
+
+    /// Rebuilds the board whose `hash` is `key`. Each column of the key holds
+    /// its tiles plus the side to move's, which only one stack of tiles fits.
+    pub fn from_key(key: u64) -> Result<Board, PositionError> {
+        let mut player_mask = 0;
+        let mut tile_mask = 0;
+        for col in 0..WIDTH {
+            let sum = get_col(key, col);
+            let height = (0..=HEIGHT as u32).find(|height| sum < (2 << height) - 1).ok_or(PositionError::InvalidMask)?;
+            let tiles = (1 << height) - 1;
+            player_mask |= (sum - tiles) << (col * 7);
+            tile_mask |= tiles << (col * 7);
+        }
+
+        let board = Board::from_masks(player_mask, tile_mask)?;
+        if board.hash() != key { return Err(PositionError::InvalidMask); }
         Ok(board)
     }
 
+    /// The board reflected left to right, which has the same score.
+    pub fn mirror(self: &Board) -> Board {
+        let reflect = |mask: u64| (0..WIDTH).fold(0, |mirrored, col| mirrored | get_col(mask, col) << ((WIDTH - 1 - col) * 7));
+        Board { player_mask: reflect(self.player_mask), tile_mask: reflect(self.tile_mask), num_actions: self.num_actions }
+    }
+
+    /// The same hash for a board and its mirror image.
+    pub fn canonical_hash(self: &Board) -> u64 {
+        self.hash().min(self.mirror().hash())
+    }
+
+    pub fn masks(self: &Board) -> (u64, u64) {
+        (self.player_mask, self.tile_mask)
+    }
+
+    pub fn to_grid(self: &Board) -> String {
+        let mut grid = String::new();
+        for r in (0..HEIGHT).rev() {
+            for c in 0..WIDTH {
+                grid.push(match self.tile_at(c, r) {
+                    Some(player) => player.symbol(),
+                    None => '.',
+                });
+            }
+            grid.push(if r == 0 { ' ' } else { '/' });
+        }
+
+        grid.push(self.side_to_move().symbol());
+        grid
+    }
+
+    pub fn status(self: &Board) -> GameStatus {
+        let last_mask = self.player_mask ^ self.tile_mask;
+        if has_four(last_mask) {
+            return GameStatus::Win { player: self.side_to_move().opponent(), cells: four_cells(last_mask) };
+        }
+
+        if has_four(self.player_mask) {
+            return GameStatus::Win { player: self.side_to_move(), cells: four_cells(self.player_mask) };
+        }
+
+        if self.num_actions == WIDTH * HEIGHT { GameStatus::Draw } else { GameStatus::Ongoing }
+    }
+
+    pub fn side_to_move(self: &Board) -> Player {
+        if self.num_actions & 1 == 0 { Player::First } else { Player::Second }
+    }
+
+    pub fn tile_at(self: &Board, col: usize, row: usize) -> Option<Player> {
+        let bit = tile_bit(col, row);
+        if self.tile_mask & bit == 0 { None }
+        else if self.player_mask & bit != 0 { Some(self.side_to_move()) }
+        else { Some(self.side_to_move().opponent()) }
+    }
+
     pub fn play_col(self: &mut Board, col: usize) {
         self.player_mask ^= self.tile_mask;
         self.tile_mask |= self.tile_mask + (1 << (col * 7));
@@ -64,25 +317,7 @@ impl Board {
     }
 
     pub fn is_winning_action(self: &Board, action: u64) -> bool {
-        let b: u64 = self.player_mask | action;
-        
-        // vertical check
-        let bb = b & (b >> 1);
-        if bb & (bb >> 2) != 0 { return true; }
-
-        // horizontal check
-        let bb = b & (b >> 7);
-        if bb & (bb >> 14) != 0 { return true; }
-
-        // diagonal 1 check
-        let bb = b & (b >> 8);
-        if bb & (bb >> 16) != 0 { return true; }
-
-        // diagonal 2 check
-        let bb = b & (b >> 6);
-        if bb & (bb >> 12) != 0 { return true; }
-
-        false
+        has_four(self.player_mask | action)
     }
 
     pub fn get_non_losing_actions(self: Board) -> u64 {
@@ -104,10 +339,45 @@ impl Board {
         winning_tile_mask(self.player_mask | action, self.tile_mask).count_ones()
     }
 
+    /// Empty tiles that would complete a four in a row for `player`.
+    pub fn threat_mask(self: Board, player: Player) -> u64 {
+        if player == self.side_to_move() {
+            winning_tile_mask(self.player_mask, self.tile_mask)
+        } else {
+            winning_tile_mask(self.player_mask ^ self.tile_mask, self.tile_mask)
+        }
+    }
+
+    pub fn odd_threat_mask(self: Board, player: Player) -> u64 {
+        self.threat_mask(player) & ODD_ROWS_MASK
+    }
+
+    pub fn even_threat_mask(self: Board, player: Player) -> u64 {
+        self.threat_mask(player) & !ODD_ROWS_MASK
+    }
+
     pub fn has_winning_action(self: Board) -> bool {
         self.playable_tile_mask() & winning_tile_mask(self.player_mask, self.tile_mask) != 0
     }
 
+    /// Playing `action` leaves the side to move two threats the opponent
+    /// cannot both stop: two it can play next, or one with another right
+    /// above. This wins on the side to move's next move but one, as long as
+    /// `action` doesn't let the opponent win first, as non-losing actions don't.
+    pub fn is_double_threat_action(self: Board, action: u64) -> bool {
+        let tile_mask = self.tile_mask | action;
+        let threats = winning_tile_mask(self.player_mask | action, tile_mask);
+        let playable = threats & (tile_mask + BOTTOM_ROW_MASK) & PLAYABLE_AREA_MASK;
+        playable.count_ones() >= 2 || playable & (threats >> 1) != 0
+    }
+
+    /// Whether `player` could still complete a four in a row, were they to
+    /// get every empty tile.
+    pub fn can_connect_four(self: Board, player: Player) -> bool {
+        let mask = if player == self.side_to_move() { self.player_mask } else { self.player_mask ^ self.tile_mask };
+        has_four(mask | (PLAYABLE_AREA_MASK & !self.tile_mask))
+    }
+
     pub fn playable_tile_mask(self: Board) -> u64 {
         (self.tile_mask + BOTTOM_ROW_MASK) & PLAYABLE_AREA_MASK
     }
@@ -141,17 +411,12 @@ impl Board {
     }
 
     pub fn print(self: &Board) {
-        let red_mask = if self.num_actions % 2 == 0 { self.player_mask } else { self.tile_mask ^ self.player_mask };
-        let yellow_mask = self.tile_mask ^ red_mask;
-
         for r in (0..HEIGHT).rev() {
             for c in 0..WIDTH {
-                if red_mask & (1 << (c * WIDTH + r)) != 0 {
-                    print!("🔴");
-                } else if yellow_mask & (1 << (c * WIDTH + r)) != 0 {
-                    print!("🟡");
-                } else {
-                    print!("⚪");
+                match self.tile_at(c, r) {
+                    Some(Player::First) => print!("🔴"),
+                    Some(Player::Second) => print!("🟡"),
+                    None => print!("⚪"),
                 }
             }
             println!();
@@ -159,11 +424,107 @@ impl Board {
     }
 }
 
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_grid())
+    }
+}
+
+impl FromStr for Board {
+    type Err = PositionError;
+
+    /// Accepts either a move sequence (`4453`) or grid notation.
+    fn from_str(s: &str) -> Result<Board, PositionError> {
+        if s.contains('/') { Board::from_grid(s) } else { Board::from_position(s.trim()) }
+    }
+}
+
+/// Lists the tiles of a mask as `(col, row)` pairs, with row 0 at the bottom.
+pub fn mask_cells(mask: u64) -> Vec<(usize, usize)> {
+    (0..WIDTH)
+        .flat_map(|c| (0..HEIGHT).map(move |r| (c, r)))
+        .filter(|(c, r)| mask & tile_bit(*c, *r) != 0)
+        .collect()
+}
+
+/// Lists the columns containing a tile of the mask.
+pub fn mask_cols(mask: u64) -> Vec<usize> {
+    (0..WIDTH).filter(|c| get_col(mask, *c) != 0).collect()
+}
+
+pub(crate) fn parse_col(c: char) -> Result<usize, PositionError> {
+    match c.to_digit(10) {
+        Some(d @ 1..=7) => Ok(d as usize - 1),
+        _ => Err(PositionError::InvalidCharacter(c)),
+    }
+}
+
+// takes back one tile of the player who moved last, searching for an order
+// in which neither player has four in a row before the final move
+fn unplay(player_mask: u64, tile_mask: u64, moves: &mut Vec<usize>, dead_ends: &mut HashSet<u64>) -> bool {
+    if tile_mask == 0 { return true; }
+    if dead_ends.contains(&(player_mask + tile_mask)) { return false; }
+
+    let last_mask = player_mask ^ tile_mask;
+    for col in 0..WIDTH {
+        let c = get_col(tile_mask, col);
+        if c == 0 { continue; }
+
+        let top = (c + 1) >> 1 << (col * 7);
+        if last_mask & top == 0 { continue; }
+
+        let prev_player_mask = last_mask ^ top;
+        if has_four(prev_player_mask) || has_four(player_mask) { continue; }
+
+        moves.push(col);
+        if unplay(prev_player_mask, tile_mask ^ top, moves, dead_ends) { return true; }
+        moves.pop();
+    }
+
+    dead_ends.insert(player_mask + tile_mask);
+    false
+}
+
+fn four_cells(b: u64) -> u64 {
+    let mut cells = 0;
+    for dir in [1, 7, 8, 6] {
+        let bb = b & (b >> dir);
+        let start = bb & (bb >> (2 * dir));
+        cells |= start | start << dir | start << (2 * dir) | start << (3 * dir);
+    }
+
+    cells
+}
+
+fn tile_bit(col: usize, row: usize) -> u64 {
+    1 << (col * 7 + row)
+}
+
 fn get_col(b: u64, c: usize) -> u64 {
     b >> (c * WIDTH) & 0b1111111
 }
 
-fn winning_tile_mask(player_mask: u64, tile_mask: u64) -> u64 {
+fn has_four(b: u64) -> bool {
+    // vertical check
+    let bb = b & (b >> 1);
+    if bb & (bb >> 2) != 0 { return true; }
+
+    // horizontal check
+    let bb = b & (b >> 7);
+    if bb & (bb >> 14) != 0 { return true; }
+
+    // diagonal 1 check
+    let bb = b & (b >> 8);
+    if bb & (bb >> 16) != 0 { return true; }
+
+    // diagonal 2 check
+    let bb = b & (b >> 6);
+    if bb & (bb >> 12) != 0 { return true; }
+
+    false
+}
+
+pub(crate) fn winning_tile_mask(player_mask: u64, tile_mask: u64) -> u64 {
     let pos = player_mask;
 
     // vertical
@@ -244,5 +605,159 @@ mod test {
 
         let board = Board::from_position("error");
         assert!(board.is_err());
+
+        let board = Board::from_position("44556671");
+        assert_eq!(board, Err(PositionError::AlreadyWon));
+    }
+
+    #[test]
+    fn test_threat_mask() {
+        let board = Board::from_position("4455").unwrap();
+        assert_eq!(mask_cells(board.threat_mask(Player::First)), vec![]);
+
+        // x threatens both ends of the bottom row, o the squares above them
+        let board = Board::from_position("445566").unwrap();
+        assert_eq!(mask_cells(board.threat_mask(Player::First)), vec![(2, 0), (6, 0)]);
+        assert_eq!(mask_cells(board.threat_mask(Player::Second)), vec![(2, 1), (6, 1)]);
+        assert_eq!(mask_cells(board.odd_threat_mask(Player::First)), vec![(2, 0), (6, 0)]);
+        assert_eq!(mask_cells(board.even_threat_mask(Player::Second)), vec![(2, 1), (6, 1)]);
+        assert_eq!(board.odd_threat_mask(Player::Second), 0);
+    }
+
+    #[test]
+    fn test_double_threats() {
+        // x makes an open three on the bottom row
+        let board = Board::from_position("4455").unwrap();
+        let actions = board.get_non_losing_actions();
+        let double: Vec<usize> = (0..WIDTH).filter(|&col| board.is_double_threat_action(Board::get_action(actions, col as i32))).collect();
+        assert_eq!(double, vec![2, 5]);
+
+        // o gets a threat it can play with another right above
+        let board = Board::from_position("2562536554").unwrap();
+        assert!(board.is_double_threat_action(Board::get_action(board.get_non_losing_actions(), 2)));
+        assert!(!board.is_double_threat_action(Board::get_action(board.get_non_losing_actions(), 6)));
+    }
+
+    #[test]
+    fn test_can_connect_four() {
+        let board = Board::new();
+        assert!(board.can_connect_four(Player::First) && board.can_connect_four(Player::Second));
+
+        // with two tiles left neither player has a line to complete
+        let board = Board::from_grid("xoxoox./oxxoxo./xooxxoo/xxoxoxx/ooxxoxx/ooxooxo x").unwrap();
+        assert!(!board.can_connect_four(Player::First) && !board.can_connect_four(Player::Second));
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(Board::new().status(), GameStatus::Ongoing);
+        assert_eq!(Board::from_position("4436212").unwrap().status(), GameStatus::Ongoing);
+
+        let status = Board::from_position("4455667").unwrap().status();
+        assert!(matches!(status, GameStatus::Win { player: Player::First, .. }));
+        assert_eq!(status.winning_cells(), vec![(3, 0), (4, 0), (5, 0), (6, 0)]);
+
+        let status = Board::from_position("1213141").unwrap().status();
+        assert_eq!(status.winning_cells(), vec![(0, 0), (0, 1), (0, 2), (0, 3)]);
+
+        let status = Board::from_position("1223343447").unwrap().status();
+        assert!(matches!(status, GameStatus::Ongoing));
+
+        let status = Board::from_position("12233434474").unwrap().status();
+        assert!(matches!(status, GameStatus::Win { player: Player::First, .. }));
+        assert_eq!(status.winning_cells(), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+        let status = Board::from_position("12121232").unwrap().status();
+        assert!(matches!(status, GameStatus::Win { player: Player::Second, .. }));
+
+        let draw = Board::from_position("111111222222333333544444455555666666777777").unwrap();
+        assert_eq!(draw.status(), GameStatus::Draw);
+    }
+
+    #[test]
+    fn test_from_grid() {
+        let board = Board::from_grid("......./......./......./......./......./....... x").unwrap();
+        assert_eq!(board, Board::new());
+
+        let board = Board::from_grid("......./......./......./......./.x.o.../oxxx.o. o").unwrap();
+        assert_eq!(board, Board::from_position("4436212").unwrap());
+
+        let board = Board::from_grid("......./......./......./......./...o.../...x... x").unwrap();
+        assert_eq!(board, Board::from_position("44").unwrap());
+
+        assert_eq!(Board::from_grid("......./...x... o"), Err(PositionError::RowCount(2)));
+        assert_eq!(Board::from_grid("......./......./......./......./......./...x.. o"), Err(PositionError::RowLength(6)));
+        assert_eq!(Board::from_grid("......./......./......./......./......./...x..y o"), Err(PositionError::InvalidCharacter('y')));
+        assert_eq!(Board::from_grid("......./......./......./......./......./...x..."), Err(PositionError::MissingSideToMove));
+        assert_eq!(Board::from_grid("......./......./......./......./......./...x... x"), Err(PositionError::SideToMove));
+        assert_eq!(Board::from_grid("......./......./......./......./......./...xx.. o"), Err(PositionError::TileCount));
+        assert_eq!(Board::from_grid("......./......./......./......./...x.../.....o. x"), Err(PositionError::FloatingTile(3)));
+        assert_eq!(Board::from_grid("......./......./......./......./......./xxxxooo o"), Board::from_position("1526374"));
+        assert_eq!(Board::from_grid("......./......./......./......./oo..x../xxxxooo x"), Err(PositionError::AlreadyWon));
+        assert_eq!(Board::from_grid("......./......x/......o/......o/......o/xxx...o x"), Err(PositionError::AlreadyWon));
+    }
+
+    #[test]
+    fn test_from_masks() {
+        let board = Board::from_position("444447533335555").unwrap();
+        let (player_mask, tile_mask) = board.masks();
+        assert_eq!(Board::from_masks(player_mask, tile_mask), Ok(board));
+
+        assert_eq!(Board::from_masks(0, 0b10), Err(PositionError::FloatingTile(0)));
+        assert_eq!(Board::from_masks(0b1, 0b1), Err(PositionError::TileCount));
+        assert_eq!(Board::from_masks(0b10, 0b1), Err(PositionError::InvalidMask));
+        assert_eq!(Board::from_masks(0, 1 << 6), Err(PositionError::InvalidMask));
+        assert_eq!(Board::from_masks(0b110, 0b1111111), Err(PositionError::InvalidMask));
+    }
+
+    #[test]
+    fn test_from_key() {
+        for position in ["", "4", "4436212", "444447533335555", "444444"] {
+            let board = Board::from_position(position).unwrap();
+            assert_eq!(Board::from_key(board.hash()), Ok(board));
+        }
+
+        let won = Board::from_position("4455667").unwrap();
+        assert_eq!(Board::from_key(won.hash()), Ok(won));
+        assert_eq!(Board::from_key(0b1111111), Err(PositionError::InvalidMask));
+        assert_eq!(Board::from_key(0b11), Err(PositionError::TileCount));
+    }
+
+    #[test]
+    fn test_mirror() {
+        let board = Board::from_position("4436212").unwrap();
+        assert_eq!(board.mirror(), Board::from_position("4452676").unwrap());
+        assert_eq!(board.mirror().mirror(), board);
+        assert_eq!(board.canonical_hash(), board.mirror().canonical_hash());
+        assert_ne!(board.hash(), board.mirror().hash());
+
+        let symmetrical = Board::from_position("4444").unwrap();
+        assert_eq!(symmetrical.mirror(), symmetrical);
+    }
+
+    #[test]
+    fn test_to_position() {
+        for position in ["", "4", "4436212", "444447533335555", "444444", "4455667"] {
+            let board = Board::from_position(position).unwrap();
+            let recovered = board.to_position().unwrap();
+            assert_eq!(recovered.len(), position.len());
+            assert_eq!(Board::from_position(&recovered), Ok(board));
+        }
+
+        // the winning tile must be played last
+        let recovered = Board::from_position("4455667").unwrap().to_position().unwrap();
+        assert!(Board::from_position(&recovered[..6]).unwrap().is_winning_col(6));
+    }
+
+    #[test]
+    fn test_display_from_str() {
+        for position in ["", "4", "4436212", "444447533335555", "444444", "4455667", "12233434474"] {
+            let board = Board::from_position(position).unwrap();
+            assert_eq!(board.to_string().parse::<Board>(), Ok(board));
+            assert_eq!(Board::from_grid(&board.to_grid()), Ok(board));
+            assert_eq!(position.parse::<Board>(), Ok(board));
+        }
+
+        assert_eq!(Board::from_position("4436212").unwrap().to_string(), "......./......./......./......./.x.o.../oxxx.o. o");
     }
 }
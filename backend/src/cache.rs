@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+use serde::Serialize;
+
+use crate::board::{Board, WIDTH};
+
+/// Score of every playable column of a position, from the side to move's
+/// point of view.
+pub type ColumnScores = [Option<i8>; WIDTH];
+
+/// Hit rate metrics of a `SolvedCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 }
+    }
+}
+
+/// Solved positions kept up to a fixed capacity, evicting the least
+/// recently used one first.
+pub struct SolvedCache {
+    capacity: usize,
+    // board hash -> (scores, last use)
+    entries: HashMap<u64, (ColumnScores, u64)>,
+    // last use -> board hash, oldest first
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl SolvedCache {
+    pub fn new(capacity: usize) -> SolvedCache {
+        SolvedCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, board: &Board) -> Option<ColumnScores> {
+        let key = board.hash();
+        let scores = match self.entries.get(&key) {
+            Some(&(scores, _)) => scores,
+            None => {
+                self.misses += 1;
+                return None;
+            },
+        };
+
+        self.hits += 1;
+        self.touch(key, scores);
+        Some(scores)
+    }
+
+    pub fn insert(&mut self, board: &Board, scores: ColumnScores) {
+        if self.capacity == 0 { return; }
+
+        let key = board.hash();
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.touch(key, scores);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits, misses: self.misses, entries: self.entries.len(), capacity: self.capacity }
+    }
+
+    /// Writes the entries to `path`, oldest first.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        SolvedCache::save_entries(&self.entries(), path)
+    }
+
+    /// The entries oldest first, as `save` writes them, to save them later
+    /// with `save_entries` without holding on to the cache.
+    pub fn entries(&self) -> Vec<(u64, ColumnScores)> {
+        self.recency.values().map(|key| (*key, self.entries[key].0)).collect()
+    }
+
+    /// Writes `entries` to a temporary file then moves it over `path`, so
+    /// a write that fails half way leaves the last saved cache whole.
+    pub fn save_entries(entries: &[(u64, ColumnScores)], path: &str) -> std::io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        let mut f = BufWriter::new(File::create(&tmp_path)?);
+        bincode::serialize_into(&mut f, entries).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    /// Reads a cache written by `save`, keeping the most recently used
+    /// entries if there are more than `capacity`.
+    pub fn load(path: &str, capacity: usize) -> std::io::Result<SolvedCache> {
+        let f = BufReader::new(File::open(path)?);
+        let entries: Vec<(u64, ColumnScores)> = bincode::deserialize_from(f).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut cache = SolvedCache::new(capacity);
+        for (key, scores) in entries.into_iter().rev().take(capacity).rev() {
+            cache.touch(key, scores);
+        }
+        Ok(cache)
+    }
+
+    // marks `key` as the most recently used entry
+    fn touch(&mut self, key: u64, scores: ColumnScores) {
+        self.clock += 1;
+        if let Some((_, last_use)) = self.entries.insert(key, (scores, self.clock)) {
+            self.recency.remove(&last_use);
+        }
+        self.recency.insert(self.clock, key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let boards: Vec<Board> = ["", "4", "44", "444"].iter().map(|pos| Board::from_position(pos).unwrap()).collect();
+        let scores = |score| [Some(score); WIDTH];
+
+        let mut cache = SolvedCache::new(3);
+        for (i, board) in boards[..3].iter().enumerate() {
+            cache.insert(board, scores(i as i8));
+        }
+        assert_eq!(cache.get(&boards[0]), Some(scores(0)));
+
+        // the second board is now the least recently used
+        cache.insert(&boards[3], scores(3));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&boards[1]), None);
+        assert_eq!(cache.get(&boards[0]), Some(scores(0)));
+        assert_eq!(cache.get(&boards[3]), Some(scores(3)));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 1, 3));
+        assert_eq!(stats.hit_rate(), 0.75);
+
+        let path = std::env::temp_dir().join(format!("connect4_cache_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        cache.save(path).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+
+        // reloading into a smaller cache keeps the most recent entries
+        let mut loaded = SolvedCache::load(path, 2).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&boards[2]), None);
+        assert_eq!(loaded.get(&boards[0]), Some(scores(0)));
+        assert_eq!(loaded.get(&boards[3]), Some(scores(3)));
+    }
+}
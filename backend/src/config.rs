@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use lib::progress::SearchLimits;
+use lib::transposition::TABLE_SIZE;
+
+const HOST: &str = "0.0.0.0";
+const PORT: u16 = 8081;
+const CACHE_SIZE: usize = 100_000;
+const WORKERS: usize = 4;
+const QUEUE_SIZE: usize = 64;
+const RATE_LIMIT: f64 = 10.0;
+const BURST: usize = 20;
+const SEARCH_TIME: Duration = Duration::from_secs(10);
+const ENV_PREFIX: &str = "CONNECT4_";
+
+const USAGE: &str = "Usage: connect4 [<db>] --webserver [--config <file>] [--host <addr>] [--port <n>] [--book <db>] [--tt-size <n>] [--cache <file>] [--cache-size <n>] [--workers <n>] [--queue <n>] [--games <file>] [--rate-limit <n>] [--burst <n>] [--search-time <ms>] [--search-nodes <n>]";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerOptions {
+    pub host: String,
+    pub port: u16,
+    // opening database, reloaded whenever the file changes
+    pub book_path: Option<String>,
+    // transposition table entries per agent
+    pub table_size: usize,
+    pub cache_path: Option<String>,
+    pub cache_size: usize,
+    // file finished games are recorded to
+    pub games_path: Option<String>,
+    // agents solving requests in parallel, each with its own transposition table
+    pub workers: usize,
+    // connections waiting for a free agent before new ones are turned away
+    pub queue: usize,
+    // requests per second allowed from each client address, 0 for no limit
+    pub rate_limit: f64,
+    pub burst: usize,
+    // budget of each solve, answered with the best move so far when spent
+    pub search_limits: SearchLimits,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            host: HOST.to_string(),
+            port: PORT,
+            book_path: None,
+            table_size: TABLE_SIZE,
+            cache_path: None,
+            cache_size: CACHE_SIZE,
+            games_path: None,
+            workers: WORKERS,
+            queue: QUEUE_SIZE,
+            rate_limit: RATE_LIMIT,
+            burst: BURST,
+            search_limits: SearchLimits { nodes: None, time: Some(SEARCH_TIME) },
+        }
+    }
+}
+
+impl ServerOptions {
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // sets the option named like its flag, without the dashes
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = || value.parse::<usize>().map_err(|_| format!("invalid number {}", value));
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| format!("invalid port {}", value))?,
+            "book" => self.book_path = Some(value.to_string()),
+            "tt-size" => self.table_size = number()?,
+            "cache" => self.cache_path = Some(value.to_string()),
+            "cache-size" => self.cache_size = number()?,
+            "games" => self.games_path = Some(value.to_string()),
+            "workers" => self.workers = number()?.max(1),
+            "queue" => self.queue = number()?,
+            "rate-limit" => self.rate_limit = value.parse().ok().filter(|rate: &f64| *rate >= 0.0).ok_or(format!("invalid rate {}", value))?,
+            "burst" => self.burst = number()?,
+            // 0 lifts the limit
+            "search-time" => self.search_limits.time = Some(number()?).filter(|&ms| ms > 0).map(|ms| Duration::from_millis(ms as u64)),
+            "search-nodes" => self.search_limits.nodes = Some(number()?).filter(|&nodes| nodes > 0).map(|nodes| nodes as u64),
+            _ => return Err("unknown option".to_string()),
+        }
+        Ok(())
+    }
+}
+
+/// Server options from, in increasing precedence, a TOML config file named
+/// by `--config` or `CONNECT4_CONFIG`, `CONNECT4_*` environment variables,
+/// the database given before `--webserver`, and the flags in `args`.
+pub fn server_options(book: Option<String>, args: &[String]) -> ServerOptions {
+    load(book, args, std::env::vars()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    })
+}
+
+fn load(book: Option<String>, args: &[String], env: impl Iterator<Item = (String, String)>) -> Result<ServerOptions, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let key = arg.strip_prefix("--").ok_or_else(|| format!("Unexpected argument {}", arg))?;
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+        flags.push((key.to_string(), value.clone()));
+    }
+
+    // CONNECT4_CACHE_SIZE sets what --cache-size does
+    let env: Vec<(String, String)> = env
+        .filter_map(|(name, value)| name.strip_prefix(ENV_PREFIX).map(|key| (key.to_lowercase().replace('_', "-"), value)))
+        .collect();
+
+    let mut options = ServerOptions::default();
+
+    let config = flags.iter().chain(env.iter()).find(|(key, _)| key == "config").map(|(_, path)| path.clone());
+    if let Some(path) = config {
+        let contents = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+        let table = contents.parse::<toml::Table>().map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                _ => return Err(format!("Invalid value for {} in {}", key, path)),
+            };
+            options.set(&key.replace('_', "-"), &value).map_err(|e| format!("Invalid {} in {}: {}", key, path, e))?;
+        }
+    }
+
+    for (key, value) in env.iter().filter(|(key, _)| key != "config") {
+        options.set(key, value).map_err(|e| format!("Invalid {}{}: {}", ENV_PREFIX, key.to_uppercase().replace('-', "_"), e))?;
+    }
+
+    if let Some(book) = book { options.book_path = Some(book); }
+
+    for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+        options.set(key, value).map_err(|e| format!("Invalid --{}: {}", key, e))?;
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(load(None, &[], env(&[("PATH", "/bin")])).unwrap(), ServerOptions::default());
+
+        let path = std::env::temp_dir().join(format!("connect4_config_{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "port = 9000\nworkers = 2\ntt_size = 1000\nbook = \"file.bin\"\n").unwrap();
+
+        // flags override the environment, which overrides the config file
+        let options = load(
+            Some("positional.bin".to_string()),
+            &strings(&["--config", path, "--workers", "8"]),
+            env(&[("CONNECT4_PORT", "9001"), ("CONNECT4_WORKERS", "3"), ("CONNECT4_CACHE_SIZE", "10")]),
+        ).unwrap();
+        assert_eq!(options.bind_addr(), "0.0.0.0:9001");
+        assert_eq!((options.workers, options.table_size, options.cache_size), (8, 1000, 10));
+        assert_eq!(options.book_path.as_deref(), Some("positional.bin"));
+
+        let options = load(None, &[], env(&[("CONNECT4_CONFIG", path)])).unwrap();
+        assert_eq!((options.port, options.book_path.as_deref()), (9000, Some("file.bin")));
+
+        std::fs::write(path, "threads = 2\n").unwrap();
+        assert!(load(None, &strings(&["--config", path]), env(&[])).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(load(None, &strings(&["--port", "http"]), env(&[])).is_err());
+        assert!(load(None, &strings(&["--workers"]), env(&[])).is_err());
+        assert!(load(None, &[], env(&[("CONNECT4_COLOUR", "red")])).is_err());
+        assert!(load(None, &strings(&["--rate-limit", "-1"]), env(&[])).is_err());
+
+        let options = load(None, &strings(&["--search-time", "0", "--search-nodes", "5000", "--rate-limit", "0.5"]), env(&[])).unwrap();
+        assert_eq!(options.search_limits, SearchLimits { nodes: Some(5000), time: None });
+        assert_eq!(options.rate_limit, 0.5);
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Write};
+use std::str::FromStr;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::agent::Agent;
+use crate::batch::{AgentPool, BatchOptions};
+use crate::board::{Board, Player, WIDTH};
+
+// the share of self-play moves picked at random rather than among the best
+const EXPLORATION: f64 = 0.1;
+// self-play games started per round, for each thread
+const GAMES_PER_THREAD: usize = 4;
+// random positions in a row already sampled before the source counts as used up
+const MAX_REPEATS: usize = 1000;
+// a position has at most 41 moves played before the game is over
+const MAX_PLY: usize = 41;
+// moves strings are padded to this length in NPY records
+const NPY_MOVES_LEN: usize = 42;
+// per column score of a full column in NPY records
+const NPY_MISSING: i8 = i8::MIN;
+
+/// Where the positions of a dataset come from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// Positions reached by playing random moves.
+    Random,
+    /// Positions of games the agent plays against itself after a random
+    /// opening, mostly picking among its best moves.
+    SelfPlay,
+    /// Positions stored in the opening book.
+    Book,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Source::Random),
+            "self-play" => Ok(Source::SelfPlay),
+            "book" => Ok(Source::Book),
+            _ => Err(format!("unknown source {}, expected random, self-play or book", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DatasetOptions {
+    pub source: Source,
+    pub samples: usize,
+    /// Moves played in the positions sampled, at least `min_ply` and at most
+    /// `max_ply`. Random playouts and self-play games start with `min_ply`
+    /// random moves.
+    pub min_ply: usize,
+    pub max_ply: usize,
+    pub seed: u64,
+    /// How positions are solved, `threads` of them at a time.
+    pub solver: BatchOptions,
+}
+
+impl Default for DatasetOptions {
+    fn default() -> Self {
+        DatasetOptions { source: Source::Random, samples: 1000, min_ply: 8, max_ply: 36, seed: 0, solver: BatchOptions::default() }
+    }
+}
+
+/// A solved position.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Sample {
+    /// Columns played from the start, numbered from 1.
+    pub moves: String,
+    /// Tiles of each player, with bit `col * 7 + row` set for a tile in the
+    /// column and row counting from 0 at the bottom left.
+    pub first: u64,
+    pub second: u64,
+    pub ply: u8,
+    /// The side to move's exact score.
+    pub score: i8,
+    /// Score of playing each column, None for full ones.
+    pub scores: [Option<i8>; WIDTH],
+}
+
+impl Sample {
+    fn new(board: &Board, moves: String, scores: [Option<i8>; WIDTH]) -> Sample {
+        let (player_mask, tile_mask) = board.masks();
+        let (first, second) = match board.side_to_move() {
+            Player::First => (player_mask, player_mask ^ tile_mask),
+            Player::Second => (player_mask ^ tile_mask, player_mask),
+        };
+
+        Sample {
+            moves,
+            first,
+            second,
+            ply: board.num_actions() as u8,
+            score: scores.iter().flatten().max().copied().unwrap(),
+            scores,
+        }
+    }
+}
+
+/// Samples positions from `options.source` and solves every column of each,
+/// leaving out positions that are a mirror image of one already sampled.
+/// Samples come sorted by ply, and there are fewer of them than asked for if
+/// the source runs out of new positions or the searches out of budget. The
+/// same seed gives the same samples whatever the number of threads, as long
+/// as every search finishes.
+pub fn generate(options: &DatasetOptions) -> std::io::Result<Vec<Sample>> {
+    let min_ply = options.min_ply.min(MAX_PLY);
+    let max_ply = options.max_ply.clamp(min_ply, MAX_PLY);
+    let pool = AgentPool::new(&options.solver);
+    let mut seen = HashSet::new();
+
+    let mut samples = match options.source {
+        Source::Book => {
+            let book = options.solver.book.as_ref().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "sampling book positions needs a book"))?;
+            let mut boards: Vec<Board> = book.positions().into_iter()
+                .map(|(board, _)| board)
+                .filter(|board| (min_ply..=max_ply).contains(&board.num_actions()))
+                .collect();
+            boards.sort_by_key(|board| board.hash());
+            boards.shuffle(&mut StdRng::seed_from_u64(options.seed));
+
+            let positions = boards.into_iter()
+                .filter(|board| seen.insert(board.canonical_hash()))
+                .take(options.samples)
+                .map(|board| (board, board.to_position().unwrap()))
+                .collect();
+            solve_all(&pool, options, positions)
+        },
+        Source::Random => {
+            let mut rng = StdRng::seed_from_u64(options.seed);
+            let mut positions = Vec::new();
+            let mut repeats = 0;
+            while positions.len() < options.samples && repeats < MAX_REPEATS {
+                match random_position(&mut rng, min_ply, max_ply) {
+                    Some((board, moves)) if seen.insert(board.canonical_hash()) => {
+                        positions.push((board, moves));
+                        repeats = 0;
+                    },
+                    _ => repeats += 1,
+                }
+            }
+            solve_all(&pool, options, positions)
+        },
+        Source::SelfPlay => {
+            let games = pool.install(rayon::current_num_threads) * GAMES_PER_THREAD;
+            let mut samples = Vec::new();
+            for round in 0.. {
+                let before = samples.len();
+                let played: Vec<Vec<(Board, Sample)>> = pool.install(|| (round * games..(round + 1) * games).into_par_iter().map(|game| {
+                    if options.solver.is_cancelled() { return Vec::new(); }
+                    self_play(&pool, &mut StdRng::seed_from_u64(options.seed << 32 ^ game as u64), min_ply, max_ply)
+                }).collect());
+
+                // games are merged in order so that the threads don't race for positions
+                for (board, sample) in played.into_iter().flatten() {
+                    if samples.len() < options.samples && seen.insert(board.canonical_hash()) { samples.push(sample); }
+                }
+
+                // a round without anything new means the positions are used up
+                if samples.len() >= options.samples || samples.len() == before || options.solver.is_cancelled() { break; }
+            }
+            samples
+        },
+    };
+
+    samples.sort_by(|a, b| (a.ply, &a.moves).cmp(&(b.ply, &b.moves)));
+    samples.truncate(options.samples);
+    Ok(samples)
+}
+
+// solves the positions in parallel, leaving out those that run out of budget
+fn solve_all(pool: &AgentPool, options: &DatasetOptions, positions: Vec<(Board, String)>) -> Vec<Sample> {
+    pool.install(|| positions.into_par_iter().filter_map(|(board, moves)| {
+        if options.solver.is_cancelled() { return None; }
+        solve(&mut pool.agent(), &board, moves)
+    }).collect())
+}
+
+fn solve(agent: &mut Agent, board: &Board, moves: String) -> Option<Sample> {
+    agent.column_scores(*board).ok().map(|(scores, _)| Sample::new(board, moves, scores))
+}
+
+// plays `ply` random moves that don't end the game, None if every move would
+fn random_moves(rng: &mut StdRng, board: &mut Board, moves: &mut String, ply: usize) -> Option<()> {
+    while board.num_actions() < ply {
+        let cols: Vec<usize> = (0..WIDTH).filter(|&col| board.is_valid_col(col) && !board.is_winning_col(col)).collect();
+        let col = *cols.choose(rng)?;
+        board.play_col(col);
+        moves.push(char::from(b'1' + col as u8));
+    }
+    Some(())
+}
+
+fn random_position(rng: &mut StdRng, min_ply: usize, max_ply: usize) -> Option<(Board, String)> {
+    let (mut board, mut moves) = (Board::new(), String::new());
+    let ply = rng.gen_range(min_ply..=max_ply);
+    random_moves(rng, &mut board, &mut moves, ply)?;
+    Some((board, moves))
+}
+
+// the positions of one game with their samples, repeats left to the caller
+fn self_play(pool: &AgentPool, rng: &mut StdRng, min_ply: usize, max_ply: usize) -> Vec<(Board, Sample)> {
+    let (mut board, mut moves) = (Board::new(), String::new());
+    let mut samples = Vec::new();
+    if random_moves(rng, &mut board, &mut moves, min_ply).is_none() { return samples; }
+
+    while board.num_actions() <= max_ply && !board.status().is_over() {
+        let scores = match pool.agent().column_scores(board) {
+            Ok((scores, _)) => scores,
+            Err(_) => return samples,
+        };
+        samples.push((board, Sample::new(&board, moves.clone(), scores)));
+
+        let best = scores.iter().flatten().max().copied();
+        let cols: Vec<usize> = if rng.gen_bool(EXPLORATION) {
+            (0..WIDTH).filter(|&col| scores[col].is_some()).collect()
+        } else {
+            (0..WIDTH).filter(|&col| scores[col] == best).collect()
+        };
+        let col = *cols.choose(rng).unwrap();
+        board.play_col(col);
+        moves.push(char::from(b'1' + col as u8));
+    }
+    samples
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DatasetFormat {
+    /// A sample per line as JSON.
+    Jsonl,
+    /// A NumPy array of records, loaded with `numpy.load`.
+    Npy,
+}
+
+impl FromStr for DatasetFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DatasetFormat::Jsonl),
+            "npy" => Ok(DatasetFormat::Npy),
+            _ => Err(format!("unknown format {}, expected jsonl or npy", s)),
+        }
+    }
+}
+
+impl DatasetFormat {
+    /// In NPY files moves are zero padded byte strings and the scores of full
+    /// columns are -128.
+    pub fn write(self, out: &mut impl Write, samples: &[Sample]) -> std::io::Result<()> {
+        match self {
+            DatasetFormat::Jsonl => {
+                for sample in samples.iter() { writeln!(out, "{}", serde_json::to_string(sample).unwrap())?; }
+                Ok(())
+            },
+            DatasetFormat::Npy => write_npy(out, samples),
+        }
+    }
+}
+
+// format version 1.0, whose header is padded so the data starts 64 byte aligned
+fn write_npy(out: &mut impl Write, samples: &[Sample]) -> std::io::Result<()> {
+    let mut header = format!(
+        "{{'descr': [('moves', 'S{}'), ('first', '<u8'), ('second', '<u8'), ('ply', 'u1'), ('score', 'i1'), ('scores', 'i1', ({},))], 'fortran_order': False, 'shape': ({},), }}",
+        NPY_MOVES_LEN,
+        WIDTH,
+        samples.len(),
+    );
+    while (10 + header.len() + 1) % 64 != 0 { header.push(' '); }
+    header.push('\n');
+
+    out.write_all(b"\x93NUMPY\x01\x00")?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+
+    for sample in samples.iter() {
+        let mut moves = [0; NPY_MOVES_LEN];
+        moves[..sample.moves.len()].copy_from_slice(sample.moves.as_bytes());
+        out.write_all(&moves)?;
+        out.write_all(&sample.first.to_le_bytes())?;
+        out.write_all(&sample.second.to_le_bytes())?;
+        out.write_all(&[sample.ply, sample.score as u8])?;
+        out.write_all(&sample.scores.map(|score| score.unwrap_or(NPY_MISSING) as u8))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::progress::CancellationToken;
+
+    fn options(source: Source, samples: usize, min_ply: usize, max_ply: usize) -> DatasetOptions {
+        DatasetOptions { source, samples, min_ply, max_ply, seed: 1, solver: BatchOptions { threads: 2, table_size: 0, ..Default::default() } }
+    }
+
+    // every sample describes its own position and is the only one of it
+    fn check(samples: &[Sample]) {
+        let mut seen = HashSet::new();
+        for sample in samples.iter() {
+            let board = Board::from_position(&sample.moves).unwrap();
+            assert_eq!(Sample::new(&board, sample.moves.clone(), sample.scores), *sample);
+            assert_eq!(board.masks().1, sample.first | sample.second);
+            assert!(seen.insert(board.canonical_hash()));
+        }
+    }
+
+    #[test]
+    fn test_generate() {
+        let samples = generate(&options(Source::Random, 30, 24, 32)).unwrap();
+        assert_eq!(samples.len(), 30);
+        assert!(samples.iter().all(|sample| (24..=32).contains(&sample.ply)));
+        assert!(samples.windows(2).all(|w| w[0].ply <= w[1].ply));
+        check(&samples);
+
+        let samples = generate(&options(Source::SelfPlay, 30, 26, 41)).unwrap();
+        assert_eq!(samples.len(), 30);
+        check(&samples);
+
+        let mut one_thread = options(Source::SelfPlay, 30, 26, 41);
+        one_thread.solver.threads = 1;
+        assert_eq!(generate(&one_thread).unwrap(), samples);
+        one_thread.source = Source::Random;
+        assert_eq!(generate(&one_thread).unwrap(), generate(&options(Source::Random, 30, 26, 41)).unwrap());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut cancelled = options(Source::SelfPlay, 30, 26, 41);
+        cancelled.solver.cancel_token = Some(token);
+        assert!(generate(&cancelled).unwrap().is_empty());
+
+        assert!(generate(&options(Source::Book, 10, 0, 10)).is_err());
+    }
+
+    #[test]
+    fn test_write_npy() {
+        let board = Board::from_position("445566").unwrap();
+        let sample = Sample::new(&board, "445566".to_string(), [Some(-1), Some(-2), Some(18), Some(-1), None, Some(-2), Some(18)]);
+        assert_eq!((sample.first, sample.second, sample.score), (1 << 21 | 1 << 28 | 1 << 35, 1 << 22 | 1 << 29 | 1 << 36, 18));
+
+        let mut out = Vec::new();
+        DatasetFormat::Npy.write(&mut out, &[sample.clone(), sample]).unwrap();
+        let header_len = u16::from_le_bytes([out[8], out[9]]) as usize;
+        assert_eq!(&out[..8], b"\x93NUMPY\x01\x00");
+        assert_eq!((10 + header_len) % 64, 0);
+        assert!(String::from_utf8_lossy(&out[10..10 + header_len]).contains("'shape': (2,)"));
+
+        let record = &out[10 + header_len..];
+        assert_eq!(record.len(), 2 * (NPY_MOVES_LEN + 8 + 8 + 1 + 1 + WIDTH));
+        assert_eq!(&record[..7], b"445566\0");
+        assert_eq!(record[NPY_MOVES_LEN + 16..NPY_MOVES_LEN + 18 + WIDTH], [6, 18, 255, 254, 18, 255, 128, 254, 18]);
+    }
+}
@@ -0,0 +1,306 @@
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use lib::agent::{self, Agent};
+use lib::board::Board;
+use lib::opening_db::{OpeningDatabase, SharedBook};
+use lib::progress::{CancellationToken, Progress, SearchLimits, SearchObserver};
+use lib::transposition::{MAX_TABLE_SIZE, MIN_TABLE_SIZE, TABLE_SIZE};
+
+type Output = Arc<Mutex<dyn Write + Send>>;
+
+fn send(out: &Output, line: &str) {
+    let mut out = out.lock().unwrap();
+    let _ = writeln!(out, "{}", line);
+    let _ = out.flush();
+}
+
+// a search running on its own thread, which hands the agent back when done
+struct Search {
+    handle: JoinHandle<Agent>,
+    token: CancellationToken,
+}
+
+/// A line based engine protocol modelled on UCI, with columns numbered from
+/// 1 as in position strings:
+///
+/// ```text
+/// uci | isready | ucinewgame | quit
+/// setoption name <TTSize|Book> value <value>
+/// position startpos [moves <moves>...]
+/// go [movetime <ms>] [nodes <n>] [infinite]
+/// analyze [movetime <ms>] [nodes <n>]
+/// stop | d
+/// ```
+///
+/// Searches run in the background, reporting `info` lines until they end
+/// with `bestmove`, or `stop` ends them early with the best move so far.
+/// Commands changing the position, options or game stop a running search
+/// the same way before they take effect, as does starting another.
+pub struct Engine {
+    // None while a search has it
+    agent: Option<Agent>,
+    search: Option<Search>,
+    board: Board,
+    book: SharedBook,
+    table_size: usize,
+    out: Output,
+}
+
+impl Engine {
+    pub fn new(book: SharedBook, out: Output) -> Engine {
+        Engine {
+            agent: Some(Agent::new(None).with_book(book.clone())),
+            search: None,
+            board: Board::new(),
+            book,
+            table_size: TABLE_SIZE,
+            out,
+        }
+    }
+
+    /// Reads commands from `input` until it ends or `quit`.
+    pub fn run(&mut self, input: impl BufRead) {
+        for line in input.lines().map_while(Result::ok) {
+            if !self.handle(&line) { break; }
+        }
+        self.stop();
+    }
+
+    /// Runs one command, false once the engine should quit.
+    pub fn handle(&mut self, line: &str) -> bool {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            [] => {},
+            ["uci"] => {
+                send(&self.out, &format!("id name connect4 {}", env!("CARGO_PKG_VERSION")));
+                send(&self.out, &format!("option name TTSize type spin default {} min {} max {}", TABLE_SIZE, MIN_TABLE_SIZE, MAX_TABLE_SIZE));
+                send(&self.out, "option name Book type string default <empty>");
+                send(&self.out, "uciok");
+            },
+            ["isready"] => send(&self.out, "readyok"),
+            ["ucinewgame"] => {
+                self.stop();
+                self.agent = Some(self.new_agent());
+                self.board = Board::new();
+            },
+            ["setoption", "name", name, "value", value @ ..] => {
+                self.stop();
+                self.set_option(name, &value.join(" "));
+            },
+            ["position", "startpos", rest @ ..] => {
+                self.stop();
+                let moves = match rest {
+                    ["moves", moves @ ..] => moves.concat(),
+                    _ => String::new(),
+                };
+                match Board::from_position(&moves) {
+                    Ok(board) => self.board = board,
+                    Err(e) => send(&self.out, &format!("info string invalid position: {}", e)),
+                }
+            },
+            ["go", args @ ..] => self.go(args, false),
+            ["analyze", args @ ..] => self.go(args, true),
+            ["stop"] => self.stop(),
+            ["d"] => send(&self.out, &self.board.to_grid()),
+            ["quit"] => return false,
+            _ => send(&self.out, &format!("info string unknown command: {}", line.trim())),
+        }
+        true
+    }
+
+    fn new_agent(&self) -> Agent {
+        Agent::new(None).with_book(self.book.clone()).with_table_size(self.table_size)
+    }
+
+    fn set_option(&mut self, name: &str, value: &str) {
+        match name {
+            "TTSize" => match value.parse::<usize>() {
+                Ok(size) if (MIN_TABLE_SIZE..=MAX_TABLE_SIZE).contains(&size) => {
+                    self.table_size = size;
+                    self.agent = Some(self.new_agent());
+                },
+                _ => send(&self.out, &format!("info string invalid TTSize: {}, expected {} to {}", value, MIN_TABLE_SIZE, MAX_TABLE_SIZE)),
+            },
+            "Book" => match OpeningDatabase::open(value) {
+                Ok(db) => {
+                    send(&self.out, &format!("info string loaded book {} to depth {}", value, db.depth()));
+                    self.book.swap(Some(Arc::new(db)));
+                },
+                Err(e) => send(&self.out, &format!("info string failed to load book {}: {}", value, e)),
+            },
+            _ => send(&self.out, &format!("info string unknown option: {}", name)),
+        }
+    }
+
+    // `analyze` scores every column where `go` stops at the first win
+    fn go(&mut self, args: &[&str], analyze: bool) {
+        self.stop();
+
+        let mut limits = SearchLimits::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            // only the limits take a value
+            let value = match *arg {
+                "movetime" | "nodes" => args.next().and_then(|value| value.parse::<u64>().ok()),
+                _ => None,
+            };
+            match (*arg, value) {
+                ("movetime", Some(ms)) => limits.time = Some(Duration::from_millis(ms)),
+                ("nodes", Some(nodes)) => limits.nodes = Some(nodes),
+                ("infinite", _) => {},
+                _ => {
+                    send(&self.out, &format!("info string invalid go argument: {}", arg));
+                    return;
+                },
+            }
+        }
+
+        let board = self.board;
+        if board.status().is_over() {
+            send(&self.out, "bestmove none");
+            return;
+        }
+
+        let token = CancellationToken::new();
+        let mut agent = self.agent.take().unwrap();
+        agent.set_limits(limits);
+        agent.set_cancel_token(Some(token.clone()));
+        agent.set_observer(Some(Arc::new(Info(self.out.clone()))));
+
+        let out = self.out.clone();
+        let handle = thread::spawn(move || {
+            let result = if !analyze { agent.best_col(board) } else {
+                agent.column_scores(board).map(|(scores, stats)| {
+                    let line: Vec<String> = scores.iter().map(|score| score.map_or("-".to_string(), |score| score.to_string())).collect();
+                    send(&out, &format!("info scores {}", line.join(" ")));
+                    let (col, score) = agent::best_column(&scores).unwrap();
+                    (col, score, stats)
+                })
+            };
+            agent.set_observer(None);
+            agent.set_cancel_token(None);
+
+            match result {
+                Ok((col, score, stats)) => {
+                    send(&out, &format!("info score {} nodes {} time {} nps {:.0}", score, stats.nodes, stats.elapsed.as_millis(), stats.nodes_per_sec()));
+                    send(&out, &format!("bestmove {}", col + 1));
+                },
+                Err(cancelled) => {
+                    send(&out, &format!("info lowerbound {} upperbound {} nodes {} time {}", cancelled.min, cancelled.max, cancelled.nodes, cancelled.elapsed.as_millis()));
+                    send(&out, &format!("bestmove {}", agent::best_so_far(&board, &cancelled) + 1));
+                },
+            }
+            agent
+        });
+        self.search = Some(Search { handle, token });
+    }
+
+    // waits for the running search to finish by itself
+    fn wait(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.agent = Some(search.handle.join().unwrap());
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(ref search) = self.search { search.token.cancel(); }
+        self.wait();
+    }
+}
+
+// reports search progress as `info` lines
+struct Info(Output);
+
+impl SearchObserver for Info {
+    fn on_progress(&self, progress: &Progress) {
+        let currmove = progress.col.map_or(String::new(), |col| format!("currmove {} ", col + 1));
+        send(&self.0, &format!(
+            "info {}lowerbound {} upperbound {} nodes {} time {}",
+            currmove,
+            progress.min,
+            progress.max,
+            progress.nodes,
+            progress.elapsed.as_millis(),
+        ));
+    }
+
+    fn on_column(&self, col: u8, score: i8) {
+        send(&self.0, &format!("info move {} score {}", col + 1, score));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_engine() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new(SharedBook::default(), out.clone());
+        let output = || String::from_utf8(std::mem::take(&mut *out.lock().unwrap())).unwrap();
+
+        engine.handle("uci");
+        engine.handle("isready");
+        let lines = output();
+        assert!(lines.starts_with("id name connect4"));
+        assert!(lines.ends_with("uciok\nreadyok\n"));
+
+        // x completes its bottom row in either of two columns
+        engine.handle("position startpos moves 445 566");
+        engine.handle("setoption name TTSize value 100000000000");
+        engine.handle("go");
+        engine.wait();
+        engine.handle("setoption name TTSize value 200000");
+        let lines = output();
+        assert!(lines.contains("info score 18 "));
+        assert!(lines.contains("info string invalid TTSize: 100000000000"));
+        assert!(lines.ends_with("bestmove 3\n"));
+
+        engine.handle("analyze");
+        engine.wait();
+        engine.handle("ucinewgame");
+        let lines = output();
+        assert!(lines.contains("info move 3 score 18\n"));
+        assert!(lines.contains("info move 7 score 18\n"));
+        assert!(lines.contains("info scores "));
+
+        // a spent budget still answers with a move
+        engine.handle("go infinite nodes 4096");
+        engine.handle("isready");
+        // let the budget run out rather than racing stop against it
+        engine.wait();
+        engine.handle("stop");
+        let lines = output();
+        assert!(lines.contains("readyok\n"));
+        assert!(lines.ends_with("bestmove 4\n"));
+
+        engine.handle("position startpos moves 8");
+        engine.handle("go wibble");
+        engine.handle("go movetime infinite");
+        let lines = output();
+        assert!(lines.contains("info string invalid position"));
+        assert!(lines.contains("info string invalid go argument: wibble"));
+        assert!(lines.contains("info string invalid go argument: movetime"));
+        assert!(!engine.handle("quit"));
+    }
+
+    #[test]
+    fn test_next_command_stops_search() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new(SharedBook::default(), out.clone());
+
+        // solving the empty board without a book takes far longer than this
+        let start = std::time::Instant::now();
+        engine.handle("go");
+        engine.handle("position startpos moves 4");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let lines = String::from_utf8(std::mem::take(&mut *out.lock().unwrap())).unwrap();
+        assert!(lines.contains("info lowerbound "));
+        assert!(lines.ends_with("bestmove 4\n"));
+        assert_eq!(engine.board, Board::from_position("4").unwrap());
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt;
+
+/// Parses `none`, `all` or a comma separated list of `names`, into whether
+/// each name was given. `kind` names the set in errors.
+pub(crate) fn parse<const N: usize>(s: &str, kind: &str, names: [&str; N]) -> Result<[bool; N], String> {
+    match s {
+        "none" => return Ok([false; N]),
+        "all" => return Ok([true; N]),
+        _ => {},
+    }
+
+    let mut flags = [false; N];
+    for name in s.split(',') {
+        match names.iter().position(|&known| known == name) {
+            Some(i) => flags[i] = true,
+            None => {
+                let (last, rest) = names.split_last().unwrap();
+                return Err(format!("unknown {} {}, expected none, all or a list of {} and {}", kind, name, rest.join(", "), last));
+            },
+        }
+    }
+    Ok(flags)
+}
+
+/// Writes the names of the flags set as `parse` reads them.
+pub(crate) fn format<const N: usize>(f: &mut fmt::Formatter, flags: [(bool, &str); N]) -> fmt::Result {
+    let names: Vec<&str> = flags.into_iter().filter(|(set, _)| *set).map(|(_, name)| name).collect();
+    if names.is_empty() { write!(f, "none") } else { write!(f, "{}", names.join(",")) }
+}
@@ -0,0 +1,133 @@
+use crate::board::{parse_col, Board, PositionError, WIDTH};
+
+/// A board together with the moves that produced it, supporting takebacks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Game {
+    board: Board,
+    moves: Vec<usize>,
+    undone: Vec<usize>,
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Game {
+    pub fn new() -> Game {
+        Game {
+            board: Board::new(),
+            moves: Vec::new(),
+            undone: Vec::new(),
+        }
+    }
+
+    pub fn from_position(position: &str) -> Result<Game, PositionError> {
+        let mut game = Game::new();
+        for c in position.chars() {
+            game.play_col(parse_col(c)?)?;
+        }
+
+        Ok(game)
+    }
+
+    /// Starts a game from a board with no known history, recovering a move
+    /// order that reaches it.
+    pub fn from_board(board: Board) -> Option<Game> {
+        board.to_position().map(|position| Game::from_position(&position).unwrap())
+    }
+
+    pub fn board(&self) -> Board {
+        self.board
+    }
+
+    pub fn moves(&self) -> &[usize] {
+        &self.moves
+    }
+
+    pub fn play_col(&mut self, col: usize) -> Result<(), PositionError> {
+        if col >= WIDTH { return Err(PositionError::InvalidColumn(col)); }
+        if self.board.status().is_over() { return Err(PositionError::AlreadyWon); }
+        if !self.board.is_valid_col(col) { return Err(PositionError::ColumnFull(col)); }
+
+        self.board.play_col(col);
+        self.moves.push(col);
+        self.undone.clear();
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> Option<usize> {
+        let col = self.moves.pop()?;
+        self.board = replay(&self.moves);
+        self.undone.push(col);
+        Some(col)
+    }
+
+    pub fn redo(&mut self) -> Option<usize> {
+        let col = self.undone.pop()?;
+        self.board.play_col(col);
+        self.moves.push(col);
+        Some(col)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.moves.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub fn to_position(&self) -> String {
+        self.moves.iter().map(|col| char::from(b'1' + *col as u8)).collect()
+    }
+}
+
+fn replay(moves: &[usize]) -> Board {
+    let mut board = Board::new();
+    for col in moves {
+        board.play_col(*col);
+    }
+
+    board
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_undo_redo() {
+        let mut game = Game::from_position("4436212").unwrap();
+        assert_eq!(game.moves(), &[3, 3, 2, 5, 1, 0, 1]);
+
+        assert_eq!(game.undo(), Some(1));
+        assert_eq!(game.undo(), Some(0));
+        assert_eq!(game.board(), Board::from_position("44362").unwrap());
+        assert_eq!(game.to_position(), "44362");
+
+        assert_eq!(game.redo(), Some(0));
+        assert_eq!(game.board(), Board::from_position("443621").unwrap());
+
+        game.play_col(6).unwrap();
+        assert!(!game.can_redo());
+        assert_eq!(game.redo(), None);
+        assert_eq!(game.to_position(), "4436217");
+
+        let mut game = Game::new();
+        assert_eq!(game.undo(), None);
+        assert!(game.play_col(7).is_err());
+
+        let mut game = Game::from_position("4455667").unwrap();
+        assert_eq!(game.play_col(0), Err(PositionError::AlreadyWon));
+    }
+
+    #[test]
+    fn test_from_board() {
+        let board = Board::from_grid("......./......./......./......./.x.o.../oxxx.o. o").unwrap();
+        let game = Game::from_board(board).unwrap();
+        assert_eq!(game.board(), board);
+        assert_eq!(game.moves().len(), 7);
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+use crate::board::{parse_col, Board, Player, WIDTH};
+use crate::session::{Difficulty, Outcome, Session};
+
+/// A finished game between a human and the agent.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GameRecord {
+    pub id: String,
+    /// Where the game was played, `web` or `cli`.
+    pub source: String,
+    pub human: Player,
+    pub difficulty: Difficulty,
+    /// Moves as a position string.
+    pub position: String,
+    /// The agent's score for each of its moves, `None` for the human's.
+    pub scores: Vec<Option<i8>>,
+    pub outcome: Outcome,
+    pub winner: Option<Player>,
+    /// Seconds since the unix epoch.
+    pub finished_at: u64,
+}
+
+impl GameRecord {
+    pub fn from_session(session: &Session) -> GameRecord {
+        let state = session.state();
+        GameRecord {
+            id: state.id,
+            source: "web".to_string(),
+            human: state.human,
+            difficulty: state.difficulty,
+            position: state.position,
+            scores: state.scores,
+            outcome: state.outcome,
+            winner: state.winner,
+            finished_at: now(),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// How the recorded games continued from one position.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PositionStats {
+    /// Games that reached the position, by any move order.
+    pub games: usize,
+    pub first_wins: usize,
+    pub second_wins: usize,
+    pub draws: usize,
+    /// How often each column was played next by the human and by the agent.
+    pub human_moves: [usize; WIDTH],
+    pub agent_moves: [usize; WIDTH],
+}
+
+/// Finished games kept in an append-only file with one JSON record per line.
+pub struct GameHistory {
+    file: File,
+    games: Vec<GameRecord>,
+}
+
+impl GameHistory {
+    /// Opens or creates the file at `path`, reading back the games already in
+    /// it. Lines that can't be read, e.g. cut short by a crash, are skipped.
+    pub fn open(path: &str) -> std::io::Result<GameHistory> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut games = Vec::new();
+        for line in contents.lines() {
+            if let Ok(game) = serde_json::from_str(line) { games.push(game); }
+        }
+
+        // start the next record on a line of its own after a torn one
+        if !contents.is_empty() && !contents.ends_with('\n') { writeln!(file)?; }
+
+        Ok(GameHistory { file, games })
+    }
+
+    pub fn record(&mut self, game: GameRecord) -> std::io::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&game).unwrap())?;
+        self.file.flush()?;
+        self.games.push(game);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    /// Up to `limit` games, most recent first.
+    pub fn recent(&self, limit: usize) -> Vec<&GameRecord> {
+        self.games.iter().rev().take(limit).collect()
+    }
+
+    pub fn position_stats(&self, board: &Board) -> PositionStats {
+        let mut stats = PositionStats::default();
+        for game in self.games.iter() {
+            let moves = match game.position.chars().map(parse_col).collect::<Result<Vec<usize>, _>>() {
+                Ok(moves) => moves,
+                Err(_) => continue,
+            };
+
+            let mut current = Board::new();
+            for i in 0..=moves.len() {
+                if current.num_actions() > board.num_actions() { break; }
+
+                if current == *board {
+                    stats.games += 1;
+                    match (game.outcome, game.winner) {
+                        (_, Some(Player::First)) => stats.first_wins += 1,
+                        (_, Some(Player::Second)) => stats.second_wins += 1,
+                        (Outcome::Draw, None) => stats.draws += 1,
+                        _ => {},
+                    }
+
+                    if let Some(&col) = moves.get(i) {
+                        if current.side_to_move() == game.human { stats.human_moves[col] += 1; } else { stats.agent_moves[col] += 1; }
+                    }
+                    break;
+                }
+
+                match moves.get(i) {
+                    Some(&col) => current.play_col(col),
+                    None => break,
+                }
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(id: &str, position: &str, outcome: Outcome, winner: Option<Player>) -> GameRecord {
+        GameRecord {
+            id: id.to_string(),
+            source: "cli".to_string(),
+            human: Player::First,
+            difficulty: Difficulty::Hard,
+            position: position.to_string(),
+            scores: position.chars().enumerate().map(|(i, _)| if i % 2 == 1 { Some(0) } else { None }).collect(),
+            outcome,
+            winner,
+            finished_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("connect4_history_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut history = GameHistory::open(path).unwrap();
+        history.record(record("a", "4455667", Outcome::Win, Some(Player::First))).unwrap();
+        history.record(record("b", "5544", Outcome::Resigned, Some(Player::Second))).unwrap();
+        history.record(record("c", "3", Outcome::Resigned, Some(Player::Second))).unwrap();
+
+        // a torn last line is skipped when reading back
+        std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(b"{\"id\": \"x\", ").unwrap();
+        let mut history = GameHistory::open(path).unwrap();
+        history.record(record("d", "1", Outcome::Resigned, Some(Player::Second))).unwrap();
+        let history = GameHistory::open(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(history.len(), 4);
+        assert_eq!(history.recent(2).iter().map(|game| game.id.as_str()).collect::<Vec<_>>(), ["d", "c"]);
+
+        // "4455" and "5544" reach the same position
+        let stats = history.position_stats(&Board::from_position("4455").unwrap());
+        assert_eq!((stats.games, stats.first_wins, stats.second_wins, stats.draws), (2, 1, 1, 0));
+        assert_eq!(stats.human_moves, [0, 0, 0, 0, 0, 1, 0]);
+
+        let stats = history.position_stats(&Board::new());
+        assert_eq!(stats.games, 4);
+        assert_eq!(stats.human_moves, [1, 0, 1, 1, 1, 0, 0]);
+        assert_eq!(stats.agent_moves, [0; WIDTH]);
+    }
+}
@@ -0,0 +1,429 @@
+// Knowledge-based proofs in the style of Victor (L. V. Allis, "A Knowledge-based
+// Approach of Connect-Four", 1988). With the first player to move, the second
+// player controls zugzwang: by answering in the same column they end up with
+// the even squares. Each rule below describes a strategy that stops a set of
+// the first player's groups, and a set of compatible rules stopping every
+// group proves the second player cannot lose.
+
+use std::fmt;
+use once_cell::sync::Lazy;
+
+use crate::board::{Board, Player, HEIGHT, PLAYABLE_AREA_MASK, ODD_ROWS_MASK, WIDTH};
+
+// search steps before giving up on finding a set of compatible rules
+const MAX_STEPS: usize = 100_000;
+
+static GROUPS: Lazy<Vec<u64>> = Lazy::new(|| {
+    let mut groups = Vec::new();
+    for col in 0..WIDTH as i32 {
+        for row in 0..HEIGHT as i32 {
+            for (dc, dr) in [(0, 1), (1, 0), (1, 1), (1, -1)] {
+                let (end_col, end_row) = (col + 3 * dc, row + 3 * dr);
+                if end_col >= WIDTH as i32 || end_row < 0 || end_row >= HEIGHT as i32 { continue; }
+
+                groups.push((0..4).fold(0, |g, i| g | square(col + i * dc, row + i * dr)));
+            }
+        }
+    }
+
+    groups
+});
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleKind {
+    Claimeven,
+    Baseinverse,
+    Vertical,
+    Aftereven,
+    Lowinverse,
+    Highinverse,
+    Baseclaim,
+    Before,
+    Specialbefore,
+}
+
+/// One application of a rule: the squares its strategy occupies and the
+/// opponent groups it refutes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub kind: RuleKind,
+    /// The squares named by the rule, in the order they are described.
+    pub squares: Vec<u64>,
+    used: u64,
+    solves: u128,
+}
+
+impl Rule {
+    fn new(kind: RuleKind, squares: Vec<u64>, used: u64, solves: u128) -> Rule {
+        Rule { kind, squares, used, solves }
+    }
+
+    pub fn num_refuted(&self) -> u32 {
+        self.solves.count_ones()
+    }
+
+    fn compatible(&self, other: &Rule) -> bool {
+        self.used & other.used == 0
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let squares = self.squares.iter().map(|sq| square_name(*sq)).collect::<Vec<String>>();
+        write!(f, "{:?} {} refutes {} group(s)", self.kind, squares.join(" "), self.num_refuted())
+    }
+}
+
+/// A set of compatible rules refuting every group of the controller's
+/// opponent, so the controller can at least draw.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Proof {
+    pub controller: Player,
+    pub rules: Vec<Rule>,
+}
+
+impl fmt::Display for Proof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} can at least draw:", self.controller.symbol())?;
+        for rule in self.rules.iter() {
+            writeln!(f, "  {}", rule)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Tries to prove that the second player cannot lose the position. Only
+/// positions with the first player to move are considered, as only then does
+/// the second player control zugzwang.
+pub fn prove(board: Board) -> Option<Proof> {
+    if board.num_actions() & 1 == 1 || board.status().is_over() { return None; }
+
+    let (opponent, tile_mask) = board.masks();
+    let controller = opponent ^ tile_mask;
+    let position = Position {
+        opponent,
+        empty: PLAYABLE_AREA_MASK & !tile_mask,
+        playable: board.playable_tile_mask(),
+        problems: GROUPS.iter().map(|g| g & controller == 0).collect(),
+    };
+
+    let mut remaining = 0u128;
+    for (i, problem) in position.problems.iter().enumerate() {
+        if *problem { remaining |= 1 << i; }
+    }
+
+    let rules = position.rules();
+    let mut chosen = Vec::new();
+    let mut steps = 0;
+    if !cover(&rules, remaining, &mut chosen, &mut steps) { return None; }
+
+    Some(Proof {
+        controller: Player::Second,
+        rules: chosen.into_iter().map(|i| rules[i].clone()).collect(),
+    })
+}
+
+struct Position {
+    opponent: u64,
+    empty: u64,
+    playable: u64,
+    problems: Vec<bool>,
+}
+
+impl Position {
+    fn rules(&self) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        self.claimevens(&mut rules);
+        self.baseinverses(&mut rules);
+        self.verticals(&mut rules);
+        self.afterevens(&mut rules);
+        self.lowinverses(&mut rules);
+        self.highinverses(&mut rules);
+        self.baseclaims(&mut rules);
+        self.befores(&mut rules);
+        rules.retain(|rule| rule.solves != 0);
+        rules
+    }
+
+    // opponent groups containing every square of the mask
+    fn solved_by(&self, squares: u64) -> u128 {
+        let mut solves = 0;
+        for (i, g) in GROUPS.iter().enumerate() {
+            if self.problems[i] && g & squares == squares { solves |= 1 << i; }
+        }
+
+        solves
+    }
+
+    // empty squares with an empty square directly above, split by the row
+    // parity of the upper square
+    fn stacked_pairs(&self, upper_even: bool) -> Vec<(u64, u64)> {
+        let parity = if upper_even { !ODD_ROWS_MASK } else { ODD_ROWS_MASK };
+        squares(self.empty & (self.empty >> 1) & PLAYABLE_AREA_MASK)
+            .into_iter()
+            .map(|lower| (lower, lower << 1))
+            .filter(|(_, upper)| upper & parity != 0)
+            .collect()
+    }
+
+    // Claimeven: the second player answers the lower square with the upper
+    // one, which is even, and so gets it.
+    fn claimevens(&self, rules: &mut Vec<Rule>) {
+        for (lower, upper) in self.stacked_pairs(true) {
+            rules.push(Rule::new(RuleKind::Claimeven, vec![lower, upper], lower | upper, self.solved_by(upper)));
+        }
+    }
+
+    // Baseinverse: of two directly playable squares the second player gets
+    // at least one, by answering one with the other.
+    fn baseinverses(&self, rules: &mut Vec<Rule>) {
+        let playable = squares(self.playable);
+        for (i, a) in playable.iter().enumerate() {
+            for b in playable[i + 1..].iter() {
+                rules.push(Rule::new(RuleKind::Baseinverse, vec![*a, *b], a | b, self.solved_by(a | b)));
+            }
+        }
+    }
+
+    // Vertical: of two stacked empty squares with the upper one odd, the
+    // second player gets at least one.
+    fn verticals(&self, rules: &mut Vec<Rule>) {
+        for (lower, upper) in self.stacked_pairs(false) {
+            rules.push(Rule::new(RuleKind::Vertical, vec![lower, upper], lower | upper, self.solved_by(lower | upper)));
+        }
+    }
+
+    // Aftereven: a second player group completed by claimevens alone. Any
+    // opponent group needing a square above the group in each of its columns
+    // comes too late.
+    fn afterevens(&self, rules: &mut Vec<Rule>) {
+        for g in self.controller_groups() {
+            let empty = squares(g & self.empty);
+            if empty.iter().any(|sq| sq & ODD_ROWS_MASK != 0 || (sq >> 1) & self.empty == 0) { continue; }
+
+            let mut used = 0;
+            let mut above = Vec::new();
+            let mut solves = 0;
+            for sq in empty.iter() {
+                let column = column_above(sq >> 1);
+                used |= column;
+                above.push(column & !(sq | sq >> 1));
+                solves |= self.solved_by(*sq);
+            }
+
+            for (i, group) in GROUPS.iter().enumerate() {
+                if self.problems[i] && above.iter().all(|column| group & column != 0) { solves |= 1 << i; }
+            }
+
+            rules.push(Rule::new(RuleKind::Aftereven, empty, used, solves));
+        }
+    }
+
+    // Lowinverse: two verticals in different columns. The second player gets
+    // one square of each vertical and one of the two upper squares.
+    fn lowinverses(&self, rules: &mut Vec<Rule>) {
+        let verticals = self.stacked_pairs(false);
+        for (i, (lower_a, upper_a)) in verticals.iter().enumerate() {
+            for (lower_b, upper_b) in verticals[i + 1..].iter() {
+                if column_of(*lower_a) == column_of(*lower_b) { continue; }
+
+                let uppers = self.solved_by(upper_a | upper_b);
+                if uppers == 0 { continue; }
+
+                let solves = uppers | self.solved_by(lower_a | upper_a) | self.solved_by(lower_b | upper_b);
+                let used = lower_a | upper_a | lower_b | upper_b;
+                rules.push(Rule::new(RuleKind::Lowinverse, vec![*lower_a, *upper_a, *lower_b, *upper_b], used, solves));
+            }
+        }
+    }
+
+    // Highinverse: two columns with three empty squares each, the middle one
+    // odd. The second player gets both upper or both middle squares, and one
+    // of the top two squares in each column.
+    fn highinverses(&self, rules: &mut Vec<Rule>) {
+        let triples = squares(self.empty & (self.empty >> 1) & (self.empty >> 2) & !ODD_ROWS_MASK & PLAYABLE_AREA_MASK);
+        for (i, a) in triples.iter().enumerate() {
+            for b in triples[i + 1..].iter() {
+                if column_of(*a) == column_of(*b) { continue; }
+
+                let (middle_a, upper_a) = (a << 1, a << 2);
+                let (middle_b, upper_b) = (b << 1, b << 2);
+                let mut solves = self.solved_by(upper_a | upper_b)
+                    | self.solved_by(middle_a | middle_b)
+                    | self.solved_by(middle_a | upper_a)
+                    | self.solved_by(middle_b | upper_b);
+                if a & self.playable != 0 { solves |= self.solved_by(a | upper_b); }
+                if b & self.playable != 0 { solves |= self.solved_by(b | upper_a); }
+
+                let used = a | middle_a | upper_a | b | middle_b | upper_b;
+                rules.push(Rule::new(RuleKind::Highinverse, vec![*a, middle_a, upper_a, *b, middle_b, upper_b], used, solves));
+            }
+        }
+    }
+
+    // Baseclaim: three directly playable squares, the middle one odd. The
+    // second player gets the first square or the one above the middle, and
+    // the middle or the third square.
+    fn baseclaims(&self, rules: &mut Vec<Rule>) {
+        let playable = squares(self.playable);
+        for b in playable.iter().filter(|sq| *sq & ODD_ROWS_MASK != 0 && (*sq << 1) & self.empty != 0) {
+            for a in playable.iter().filter(|sq| *sq != b) {
+                for c in playable.iter().filter(|sq| *sq != b && *sq != a) {
+                    let solves = self.solved_by(a | b << 1) | self.solved_by(b | c);
+                    rules.push(Rule::new(RuleKind::Baseclaim, vec![*a, *b, *c], a | b | b << 1 | c, solves));
+                }
+            }
+        }
+    }
+
+    // Before: a second player group with, below each empty square's upper
+    // neighbour, a claimeven or vertical. The opponent can only get every
+    // square above the group once the group itself is complete.
+    // Specialbefore: the same, with one directly playable empty square of the
+    // group replaced by a baseinverse with another directly playable square.
+    fn befores(&self, rules: &mut Vec<Rule>) {
+        for g in self.controller_groups() {
+            let empty = squares(g & self.empty);
+            let columns = empty.iter().fold(0, |c, sq| c | column_above(column_base(*sq)));
+            if empty.iter().any(|sq| (sq << 1) & self.empty == 0) { continue; }
+            if empty.iter().any(|sq| (sq << 1) & g != 0) { continue; }
+
+            let mut above = 0;
+            let mut used = 0;
+            let mut solves = 0;
+            for sq in empty.iter() {
+                above |= sq << 1;
+                used |= sq | sq << 1;
+                solves |= if sq & ODD_ROWS_MASK != 0 { self.solved_by(sq << 1) } else { self.solved_by(sq | sq << 1) };
+            }
+
+            rules.push(Rule::new(RuleKind::Before, empty.clone(), used, solves | self.solved_by(above)));
+
+            for s in empty.iter().filter(|sq| *sq & self.playable != 0) {
+                for p in squares(self.playable & !columns) {
+                    let mut solves = self.solved_by(above | p) | self.solved_by(s | p);
+                    for sq in empty.iter().filter(|sq| *sq != s) {
+                        solves |= if sq & ODD_ROWS_MASK != 0 { self.solved_by(sq << 1) } else { self.solved_by(sq | sq << 1) };
+                    }
+
+                    let mut squares = empty.clone();
+                    squares.push(p);
+                    rules.push(Rule::new(RuleKind::Specialbefore, squares, used | p, solves));
+                }
+            }
+        }
+    }
+
+    // groups the second player could still complete, with at least one empty square
+    fn controller_groups(&self) -> Vec<u64> {
+        GROUPS.iter().copied().filter(|g| g & self.opponent == 0 && g & self.empty != 0).collect()
+    }
+}
+
+// finds compatible rules refuting every remaining group, always branching on
+// the group with the fewest candidate rules
+fn cover(rules: &[Rule], remaining: u128, chosen: &mut Vec<usize>, steps: &mut usize) -> bool {
+    if remaining == 0 { return true; }
+
+    *steps += 1;
+    if *steps > MAX_STEPS { return false; }
+
+    let candidates = |group: u32| -> Vec<usize> {
+        (0..rules.len())
+            .filter(|i| rules[*i].solves & (1 << group) != 0)
+            .filter(|i| chosen.iter().all(|j| rules[*i].compatible(&rules[*j])))
+            .collect()
+    };
+
+    let mut best: Option<Vec<usize>> = None;
+    let mut groups = remaining;
+    while groups != 0 {
+        let group = groups.trailing_zeros();
+        groups &= groups - 1;
+
+        let c = candidates(group);
+        if c.is_empty() { return false; }
+        if best.as_ref().is_none_or(|b| c.len() < b.len()) { best = Some(c); }
+    }
+
+    for i in best.unwrap() {
+        chosen.push(i);
+        if cover(rules, remaining & !rules[i].solves, chosen, steps) { return true; }
+        chosen.pop();
+    }
+
+    false
+}
+
+fn square(col: i32, row: i32) -> u64 {
+    1 << (col * 7 + row)
+}
+
+fn squares(mask: u64) -> Vec<u64> {
+    let mut squares = Vec::new();
+    let mut mask = mask;
+    while mask != 0 {
+        squares.push(mask & mask.wrapping_neg());
+        mask &= mask - 1;
+    }
+
+    squares
+}
+
+fn column_of(sq: u64) -> u32 {
+    sq.trailing_zeros() / 7
+}
+
+fn column_base(sq: u64) -> u64 {
+    1 << (column_of(sq) * 7)
+}
+
+// the square and every square above it in the same column
+fn column_above(sq: u64) -> u64 {
+    (column_base(sq) << HEIGHT) - sq
+}
+
+/// Names a square like `d1`, with columns `a` to `g` and rows counted from 1.
+pub fn square_name(sq: u64) -> String {
+    let i = sq.trailing_zeros();
+    format!("{}{}", char::from(b'a' + (i / 7) as u8), i % 7 + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(GROUPS.len(), 69);
+        assert!(GROUPS.iter().all(|g| g.count_ones() == 4 && g & !PLAYABLE_AREA_MASK == 0));
+    }
+
+    #[test]
+    fn test_square_helpers() {
+        assert_eq!(square_name(square(3, 0)), "d1");
+        assert_eq!(square_name(square(6, 5)), "g6");
+        assert_eq!(column_above(square(2, 3)), square(2, 3) | square(2, 4) | square(2, 5));
+        assert_eq!(squares(0b1010), vec![0b10, 0b1000]);
+    }
+
+    #[test]
+    fn test_prove() {
+        // only positions with the first player to move are considered
+        assert!(prove(Board::from_position("4").unwrap()).is_none());
+
+        // the first player wins the empty board, so no proof can exist
+        assert!(prove(Board::new()).is_none());
+
+        // x to move completes a vertical four
+        assert!(prove(Board::from_position("434343").unwrap()).is_none());
+
+        // o follows up in columns d, f and g to stop every remaining x group
+        let proof = prove(Board::from_position("1233722555341451114725221333").unwrap()).unwrap();
+        assert_eq!(proof.controller, Player::Second);
+        assert!(proof.rules.iter().all(|rule| rule.kind == RuleKind::Claimeven));
+        assert_eq!(proof.to_string().lines().next(), Some("o can at least draw:"));
+        assert!(proof.to_string().contains("Claimeven f1 f2"));
+    }
+}
@@ -1,5 +1,20 @@
 pub mod board;
+pub mod analysis;
+pub mod knowledge;
+pub mod game;
+pub mod session;
+pub mod history;
 pub mod agent;
+pub mod stats;
+pub mod cache;
+pub mod progress;
 pub mod transposition;
 pub mod action_ordering;
-pub mod opening_db;
\ No newline at end of file
+mod flags;
+pub mod opening_db;
+pub mod benchmark;
+pub mod rpc;
+pub mod batch;
+pub mod dataset;
+#[cfg(feature = "nn")]
+pub mod nn;
@@ -1,131 +1,549 @@
-use std::io::{BufReader, Write};
-use std::net::TcpListener;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::info;
 
-use lib::board::Board;
-use lib::agent::Agent;
-use lib::opening_db::OpeningDatabase;
-use std::io::BufRead;
+use lib::benchmark::{self, BenchmarkReport};
+use lib::board::{GameStatus, Player};
+use lib::game::Game;
+use lib::history::{self, GameHistory, GameRecord};
+use lib::session::{Difficulty, Outcome};
+use lib::progress::Progress;
+use lib::agent::{Agent, Pruning};
+use lib::action_ordering::MoveOrdering;
+use lib::opening_db::{OpeningDatabase, SharedBook};
+use lib::batch::{self, BatchFormat, BatchOptions};
+use lib::dataset::{self, DatasetFormat, DatasetOptions};
+use lib::rpc;
+#[cfg(feature = "nn")]
+use lib::nn::{Network, DEFAULT_ORDERING_PLY};
+use lib::transposition::TABLE_SIZE;
+
+use crate::engine::Engine;
+
+mod config;
+mod engine;
+mod metrics;
+mod rate_limit;
+mod server;
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
 
-    let db_file = if args.len() >= 2 { Some(args[1].clone()) } else { None };
-    let db = db_file.as_ref().map(|db_file|  {
-            println!("Loading database: {}", db_file);
-            OpeningDatabase::load(db_file.to_string())
+    if args.len() >= 2 && args[1] == "bench" {
+        bench(&args[2..]);
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "engine" {
+        engine(&args[2..]);
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "batch" {
+        batch(&args[2..]);
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "dataset" {
+        dataset(&args[2..]);
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "rpc" {
+        rpc(&args[2..]);
+        return;
+    }
+
+    // `connect4 [<db>] --webserver [options...]`
+    let webserver_args = match (args.get(1).map(String::as_str), args.get(2).map(String::as_str)) {
+        (Some("--webserver"), _) => Some((None, &args[2..])),
+        (Some(db_file), Some("--webserver")) => Some((Some(db_file.to_string()), &args[3..])),
+        _ => None,
+    };
+    if let Some((db_file, args)) = webserver_args {
+        // RUST_LOG picks the level, e.g. `debug` to log every response
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+        let options = config::server_options(db_file, args);
+        info!("Starting webserver");
+        server::webserver(options);
+        return;
+    }
+
+    // `connect4 [<db>] [--games <file>]`, where a database comes first if given
+    let usage = "Usage: connect4 [<db>] [--webserver ... | --games <file>]";
+    let mut db_file = None;
+    let mut games_file = None;
+    let mut rest = args.iter().skip(1).peekable();
+    if let Some(arg) = rest.next_if(|arg| !arg.starts_with("--")) { db_file = Some(arg.clone()); }
+    while let Some(arg) = rest.next() {
+        match (arg.as_str(), rest.next()) {
+            ("--games", Some(path)) => games_file = Some(path.clone()),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(1);
+            },
         }
-    );
-    
-    let mut agent = Agent::new(db.as_ref());
+    }
+
+    let db = db_file.map(|db_file| {
+        println!("Loading database: {}", db_file);
+        Arc::new(OpeningDatabase::load(db_file))
+    });
+
+    let history = games_file.map(|path| GameHistory::open(&path).unwrap_or_else(|e| {
+        eprintln!("Failed to open game history {}: {}", path, e);
+        std::process::exit(1);
+    }));
+
+    let mut agent = Agent::new(db);
     println!("Agent initialized!");
-    
-    // check for webserver flag
-    if args.len() >= 3 && args[2] == "--webserver" {
-        println!("Starting webserver...");
-        webserver(&mut agent);
-    } else {
-        cli(&mut agent);
+    cli(&mut agent, history);
+}
+
+const BENCHMARK_FILES: [&str; 5] = [
+    "benchmarks/Test_L3_R1",
+    "benchmarks/Test_L2_R1",
+    "benchmarks/Test_L2_R2",
+    "benchmarks/Test_L1_R1",
+    "benchmarks/Test_L1_R2",
+];
+
+// exits with the usage when a flag's value does not parse
+fn parse_value<T: FromStr>(arg: &str, value: String, usage: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid {} {}", arg, value);
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    })
+}
+
+// a benchmark run's name and how it sets up each agent
+type Variant = (String, Box<dyn Fn(Agent) -> Agent>);
+
+fn bench(args: &[String]) {
+    let usage = "Usage: connect4 bench [--book <db>] [--limit <n>] [--json <file>] [--baseline <file>] [--tolerance <pct>] [--ordering <sources>]... [--pruning <techniques>]... [--knowledge] [--nn <weights>] [--nn-ply <n>] [files...]";
+
+    let mut db_file = None;
+    let mut limit = None;
+    let mut json_file = None;
+    let mut baseline_file = None;
+    let mut tolerance = 10.0;
+    let mut orderings = Vec::new();
+    let mut prunings = Vec::new();
+    let mut knowledge = false;
+    let mut nn_file: Option<String> = None;
+    let mut nn_ply = None;
+    let mut files = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        });
+
+        match arg.as_str() {
+            "--book" => db_file = Some(value()),
+            "--limit" => limit = Some(parse_value(arg, value(), usage)),
+            "--json" => json_file = Some(value()),
+            "--baseline" => baseline_file = Some(value()),
+            "--tolerance" => tolerance = parse_value(arg, value(), usage),
+            "--ordering" => orderings.push(value().parse::<MoveOrdering>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            })),
+            "--pruning" => prunings.push(value().parse::<Pruning>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            })),
+            "--knowledge" => knowledge = true,
+            "--nn" => nn_file = Some(value()),
+            "--nn-ply" => nn_ply = Some(parse_value(arg, value(), usage)),
+            _ if arg.starts_with("--") => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            },
+            _ => files.push(arg.clone()),
+        }
+    }
+
+    if files.is_empty() {
+        files = BENCHMARK_FILES.iter().map(|file| file.to_string()).collect();
+    }
+
+    let db = db_file.map(|db_file| {
+        println!("Loading database: {}", db_file);
+        Arc::new(OpeningDatabase::open(&db_file).unwrap_or_else(|e| {
+            eprintln!("Failed to load database {}: {}", db_file, e);
+            std::process::exit(1);
+        }))
+    });
+
+    // every file runs without the book, and again with it when one is given
+    let mut books = vec![None];
+    if db.is_some() { books.push(db.clone()); }
+
+    // and again with each move ordering, pruning, the knowledge rules and the network when given
+    let mut variants: Vec<Variant> = vec![(String::new(), Box::new(|agent| agent))];
+    for ordering in orderings {
+        variants.push((format!("ordering {}", ordering), Box::new(move |agent: Agent| agent.with_ordering(ordering))));
+    }
+    for pruning in prunings {
+        variants.push((format!("pruning {}", pruning), Box::new(move |agent: Agent| agent.with_pruning(pruning))));
+    }
+    if knowledge { variants.push(("knowledge".to_string(), Box::new(|agent: Agent| agent.with_knowledge(true)))); }
+    if let Some(nn_file) = nn_file { variants.push(nn_variant(&nn_file, nn_ply)); }
+
+    let mut reports = Vec::new();
+    for file in files.iter() {
+        for book in books.iter() {
+            for (variant, configure) in variants.iter() {
+                let report = benchmark::run_variant(file, book.clone(), limit, variant, configure).unwrap();
+                println!("{}", report);
+                reports.push(report);
+            }
+        }
+    }
+
+    if let Some(json_file) = json_file {
+        std::fs::write(&json_file, serde_json::to_string_pretty(&reports).unwrap()).unwrap();
+        println!("Wrote results to: {}", json_file);
+    }
+
+    if let Some(baseline_file) = baseline_file {
+        let baseline: Vec<BenchmarkReport> = serde_json::from_str(&std::fs::read_to_string(baseline_file).unwrap()).unwrap();
+        let regressions = benchmark::compare(&reports, &baseline, tolerance / 100.0);
+        for regression in regressions.iter() {
+            println!("Regression: {}", regression);
+        }
+
+        if !regressions.is_empty() { std::process::exit(1); }
+        println!("No regressions against baseline");
+    }
+}
+
+#[cfg(feature = "nn")]
+fn nn_variant(nn_file: &str, max_ply: Option<usize>) -> Variant {
+    println!("Loading network: {}", nn_file);
+    let network = Arc::new(Network::load(nn_file).unwrap_or_else(|e| {
+        println!("Failed to load network {}: {}", nn_file, e);
+        std::process::exit(1);
+    }));
+
+    let max_ply = max_ply.unwrap_or(DEFAULT_ORDERING_PLY);
+    (format!("nn to ply {}", max_ply), Box::new(move |agent: Agent| agent.with_evaluator(Some(network.clone()), max_ply)))
+}
+
+#[cfg(not(feature = "nn"))]
+fn nn_variant(_nn_file: &str, _max_ply: Option<usize>) -> Variant {
+    println!("Built without the nn feature, rebuild with --features nn to use --nn");
+    std::process::exit(2);
+}
+
+// speaks the engine protocol over stdin and stdout, see `engine::Engine`
+fn engine(args: &[String]) {
+    let usage = "Usage: connect4 engine [--book <db>] [--tt-size <n>]";
+    let out: Arc<Mutex<dyn Write + Send>> = Arc::new(Mutex::new(std::io::stdout()));
+    let mut engine = Engine::new(SharedBook::default(), out);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        });
+
+        match arg.as_str() {
+            "--book" => engine.handle(&format!("setoption name Book value {}", value)),
+            "--tt-size" => engine.handle(&format!("setoption name TTSize value {}", value)),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            },
+        };
     }
+
+    engine.run(std::io::stdin().lock());
 }
 
-fn webserver(agent: &mut Agent) {
-    let listener = TcpListener::bind("0.0.0.0:8081").unwrap();
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        println!("Connection established!");
-
-        let buf_reader = BufReader::new(&stream);
-        let http_req: Vec<_> = buf_reader
-            .lines()
-            .map(|result| result.unwrap())
-            .take_while(|line| !line.is_empty())
-            .collect();
-
-        println!("Request: {:#?}", http_req);
-
-        if http_req[0].split(" ").collect::<Vec<&str>>()[0] != "GET" {
-            let response = "HTTP/1.1 400 Bad Request\r\n\r\n";
-            stream.write_all(response.as_bytes()).unwrap();
-            println!("Response: {:#?}\n", response);
+// solves the positions of a file, or stdin, in parallel, writing a result
+// per line to stdout as each is solved
+fn batch(args: &[String]) {
+    let usage = "Usage: connect4 batch [--book <db>] [--tt-size <n>] [--threads <n>] [--format csv|jsonl] [--search-time <ms>] [--search-nodes <n>] [<file>]";
+    let exit = |message: &str| -> ! {
+        eprintln!("{}", message);
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    };
+
+    let mut options = BatchOptions::default();
+    let mut db_file = None;
+    let mut format = BatchFormat::Csv;
+    let mut file = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            file = Some(arg.clone());
             continue;
         }
 
-        let get_req = http_req[0].split(" ").collect::<Vec<&str>>()[1].split("/").collect::<Vec<&str>>();
-        if get_req.len() < 2 || get_req[1] != "api" {
-            let response = "HTTP/1.1 404 Not Found\r\n\r\n";
-            stream.write_all(response.as_bytes()).unwrap();
-            println!("Response: {:#?}\n", response);
-            continue;
+        let value = args.next().unwrap_or_else(|| exit(&format!("Missing value for {}", arg)));
+        let number = || value.parse::<u64>().unwrap_or_else(|_| exit(&format!("Invalid {} {}", arg, value)));
+        match arg.as_str() {
+            "--book" => db_file = Some(value.clone()),
+            "--tt-size" => options.table_size = number() as usize,
+            "--threads" => options.threads = number() as usize,
+            "--format" => format = value.parse().unwrap_or_else(|e: String| exit(&e)),
+            "--search-time" => options.limits.time = Some(Duration::from_millis(number())),
+            "--search-nodes" => options.limits.nodes = Some(number()),
+            _ => exit(&format!("Unknown option {}", arg)),
+        }
+    }
+
+    let input = match file.as_deref() {
+        None | Some("-") => std::io::read_to_string(std::io::stdin()),
+        Some(file) => std::fs::read_to_string(file),
+    };
+    let positions = input.and_then(|input| batch::parse_positions(&input)).unwrap_or_else(|e| {
+        eprintln!("Failed to read positions: {}", e);
+        std::process::exit(1);
+    });
+
+    // stdout carries only results
+    options.book = db_file.map(|db_file| Arc::new(OpeningDatabase::open(&db_file).unwrap_or_else(|e| {
+        eprintln!("Failed to load database {}: {}", db_file, e);
+        std::process::exit(1);
+    })));
+
+    let start = Instant::now();
+    let (mut nodes, mut mismatches, mut errors) = (0, 0, 0);
+    let mut stdout = std::io::stdout().lock();
+    format.write_header(&mut stdout).unwrap();
+    batch::solve_batch(&positions, &options, |result| {
+        nodes += result.nodes;
+        if result.is_mismatch() { mismatches += 1; }
+        if result.error.is_some() { errors += 1; }
+        format.write(&mut stdout, &result).unwrap();
+        stdout.flush().unwrap();
+    });
+
+    let elapsed = start.elapsed();
+    eprintln!(
+        "Solved {} positions in {:.2?} ({} nodes, {:.0} nodes/s), {} mismatches, {} errors",
+        positions.len(),
+        elapsed,
+        nodes,
+        nodes as f64 / elapsed.as_secs_f64(),
+        mismatches,
+        errors,
+    );
+    if mismatches > 0 { std::process::exit(1); }
+}
+
+// writes solved positions for training evaluators
+fn dataset(args: &[String]) {
+    let usage = "Usage: connect4 dataset [--source random|self-play|book] [--samples <n>] [--min-ply <n>] [--max-ply <n>] [--seed <n>] [--book <db>] [--tt-size <n>] [--threads <n>] [--search-time <ms>] [--search-nodes <n>] [--format jsonl|npy] [--out <file>]";
+    let exit = |message: &str| -> ! {
+        eprintln!("{}", message);
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    };
+
+    let mut options = DatasetOptions::default();
+    let mut db_file = None;
+    let mut format = DatasetFormat::Jsonl;
+    let mut out_file = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| exit(&format!("Missing value for {}", arg)));
+        let number = || value.parse::<u64>().unwrap_or_else(|_| exit(&format!("Invalid {} {}", arg, value)));
+        match arg.as_str() {
+            "--source" => options.source = value.parse().unwrap_or_else(|e: String| exit(&e)),
+            "--samples" => options.samples = number() as usize,
+            "--min-ply" => options.min_ply = number() as usize,
+            "--max-ply" => options.max_ply = number() as usize,
+            "--seed" => options.seed = number(),
+            "--book" => db_file = Some(value.clone()),
+            "--tt-size" => options.solver.table_size = number() as usize,
+            "--threads" => options.solver.threads = number() as usize,
+            "--search-time" => options.solver.limits.time = Some(Duration::from_millis(number())),
+            "--search-nodes" => options.solver.limits.nodes = Some(number()),
+            "--format" => format = value.parse().unwrap_or_else(|e: String| exit(&e)),
+            "--out" => out_file = Some(value.clone()),
+            _ => exit(&format!("Unknown option {}", arg)),
         }
+    }
+
+    options.solver.book = db_file.map(|db_file| Arc::new(OpeningDatabase::open(&db_file).unwrap_or_else(|e| {
+        eprintln!("Failed to load database {}: {}", db_file, e);
+        std::process::exit(1);
+    })));
+
+    let start = Instant::now();
+    let samples = dataset::generate(&options).unwrap_or_else(|e| {
+        eprintln!("Failed to generate dataset: {}", e);
+        std::process::exit(1);
+    });
 
-        let pos = if get_req.len() < 3 { "" } else { get_req[2] };
-        let board = Board::from_position(pos);
-        match board {
-            Ok(board) => {
-                let (col, score) = agent.best_col(board);
-                let response = format!("HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\n\r\n{{\"col\": {}, \"score\": {}}}\n", col, score);
-                stream.write_all(response.as_bytes()).unwrap();
-                println!("Response: {:#?}\n", response);
+    let written = match out_file {
+        Some(ref path) => std::fs::File::create(path).and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            format.write(&mut out, &samples).and_then(|_| out.flush())
+        }),
+        None => format.write(&mut std::io::stdout().lock(), &samples),
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write dataset: {}", e);
+        std::process::exit(1);
+    }
+
+    eprintln!("Wrote {} of {} samples in {:.2?}", samples.len(), options.samples, start.elapsed());
+}
+
+// answers JSON-RPC requests read from stdin one per line, see `lib::rpc`
+fn rpc(args: &[String]) {
+    let usage = "Usage: connect4 rpc [--book <db>] [--tt-size <n>] [--schema]";
+
+    let mut db_file = None;
+    let mut table_size = TABLE_SIZE;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        });
+
+        match arg.as_str() {
+            "--book" => db_file = Some(value()),
+            "--tt-size" => table_size = parse_value(arg, value(), usage),
+            "--schema" => {
+                println!("{}", serde_json::to_string_pretty(&rpc::schema()).unwrap());
+                return;
             },
-            Err(_) => {
-                // invalid board position
-                let response = "HTTP/1.1 400 Bad Request\r\n\r\n";
-                stream.write_all(response.as_bytes()).unwrap();
-                println!("Response: {:#?}\n", response);
-            }
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            },
+        }
+    }
+
+    // stdout carries only responses
+    let db = db_file.map(|db_file| Arc::new(OpeningDatabase::open(&db_file).unwrap_or_else(|e| {
+        eprintln!("Failed to load database {}: {}", db_file, e);
+        std::process::exit(1);
+    })));
+
+    let mut agent = Agent::new(db).with_table_size(table_size);
+    let mut stdout = std::io::stdout().lock();
+    for line in std::io::stdin().lines().map_while(Result::ok) {
+        if line.trim().is_empty() { continue; }
+        if let Some(response) = rpc::handle(&mut agent, &line) {
+            writeln!(stdout, "{}", response).unwrap();
+            stdout.flush().unwrap();
         }
     }
 }
 
-fn cli(agent: &mut Agent) {
-    let mut board = Board::new();
+fn cli(agent: &mut Agent, mut history: Option<GameHistory>) {
+    let mut game = Game::new();
+    // the agent's score for each move, None for the player's
+    let mut scores: Vec<Option<i8>> = Vec::new();
+
+    agent.set_observer(Some(Arc::new(|progress: &Progress| {
+        let col = progress.col.map_or(String::new(), |col| format!("column {}, ", col + 1));
+        print!("\rThinking... {}score {} to {}, {} nodes, {:.1?}        ", col, progress.min, progress.max, progress.nodes, progress.elapsed);
+        std::io::stdout().flush().unwrap();
+    })));
 
     loop {
         println!("Player turn:");
-        board.print();
-        println!("Enter your move: ");
+        game.board().print();
+        println!("Enter your move (or 'undo'): ");
         let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        let col: usize = (input.trim().parse::<usize>().unwrap()) - 1;
-        if col > 6  || !board.is_valid_col(col) {
-            println!("Invalid move");
+        if std::io::stdin().read_line(&mut input).unwrap() == 0 { break; }
+
+        if input.trim() == "undo" {
+            // take back the agent's reply together with the player's move
+            game.undo();
+            game.undo();
+            scores.truncate(game.moves().len());
+            println!();
             continue;
         }
 
-        let win = board.is_winning_col(col);
-        board.play_col(col);
+        let col = match input.trim().parse::<usize>() {
+            Ok(col @ 1..=7) if game.board().is_valid_col(col - 1) => col - 1,
+            _ => {
+                println!("Invalid move");
+                continue;
+            }
+        };
 
-        if win {
-            println!("Player wins!");
-            break;
-        }
+        game.play_col(col).unwrap();
+        scores.push(None);
 
-        if board.num_actions() == 42 {
-            println!("Draw!");
-            break;
+        match game.board().status() {
+            GameStatus::Win { .. } => {
+                println!("Player wins!");
+                break;
+            },
+            GameStatus::Draw => {
+                println!("Draw!");
+                break;
+            },
+            GameStatus::Ongoing => {},
         }
 
         println!();
 
         println!("Agent turn:");
-        let (col, score) = agent.best_col(board);
-        let win = board.is_winning_col(col as usize);
-        board.play_col(col as usize);
+        let (col, score, stats) = agent.best_col(game.board()).unwrap();
+        println!();
+        game.play_col(col as usize).unwrap();
+        scores.push(Some(score));
 
         println!("Agent played column: {}", col + 1);
         println!("Score: {}", score);
-        board.print();
+        println!("Searched {}", stats);
+        game.board().print();
         
-        if win {
-            println!("Agent wins!");
-            break;
-        }
-
-        if board.num_actions() == 42 {
-            println!("Draw!");
-            break;
+        match game.board().status() {
+            GameStatus::Win { .. } => {
+                println!("Agent wins!");
+                break;
+            },
+            GameStatus::Draw => {
+                println!("Draw!");
+                break;
+            },
+            GameStatus::Ongoing => {},
         }
 
         println!()
     }
+
+    println!("Position: {}", game.to_position());
+
+    let status = game.board().status();
+    if let (Some(history), true) = (history.as_mut(), status.is_over()) {
+        let (outcome, winner) = Outcome::of(status);
+        let record = GameRecord {
+            id: format!("cli-{}", history::now()),
+            source: "cli".to_string(),
+            human: Player::First,
+            difficulty: Difficulty::Hard,
+            position: game.to_position(),
+            scores,
+            outcome,
+            winner,
+            finished_at: history::now(),
+        };
+        history.record(record).unwrap();
+        println!("Game recorded");
+    }
 }
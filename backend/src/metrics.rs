@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use lib::cache::CacheStats;
+use lib::stats::SearchStats;
+
+const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+const NODE_BUCKETS: [f64; 8] = [1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Histogram {
+    bounds: &'static [f64],
+    // observations at or below each bound, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) { self.counts[i] += 1; }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count).unwrap();
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        writeln!(out, "{}_sum{} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, labels, self.count).unwrap();
+    }
+}
+
+/// Counters for the `/metrics` endpoint, in the Prometheus text format.
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    nodes: Mutex<Histogram>,
+    search: Mutex<SearchStats>,
+    cancelled: AtomicU64,
+    timed_out: AtomicU64,
+    rejected: AtomicU64,
+    limited: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            latency: Mutex::new(BTreeMap::new()),
+            nodes: Mutex::new(Histogram::new(&NODE_BUCKETS)),
+            search: Mutex::new(SearchStats::default()),
+            cancelled: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe_request(&self, route: &'static str, status: u16, latency: Duration) {
+        *self.requests.lock().unwrap().entry((route, status)).or_default() += 1;
+        self.latency.lock().unwrap().entry(route).or_insert_with(|| Histogram::new(&LATENCY_BUCKETS)).observe(latency.as_secs_f64());
+    }
+
+    pub fn observe_search(&self, stats: &SearchStats) {
+        self.nodes.lock().unwrap().observe(stats.nodes as f64);
+        *self.search.lock().unwrap() += *stats;
+    }
+
+    pub fn search_cancelled(&self) {
+        self.cancelled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A search that ran out of its budget before proving the score.
+    pub fn search_timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection turned away because every agent was busy.
+    pub fn request_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection turned away because its client sent too many requests.
+    pub fn request_limited(&self) {
+        self.limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, cache: CacheStats, sessions: usize) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "connect4_requests_total", "counter", "Requests handled, by route and status.");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "connect4_requests_total{{route=\"{}\",status=\"{}\"}} {}", route, status, count).unwrap();
+        }
+
+        header(&mut out, "connect4_rejected_requests_total", "counter", "Connections turned away because the queue was full.");
+        writeln!(out, "connect4_rejected_requests_total {}", self.rejected.load(Ordering::Relaxed)).unwrap();
+
+        header(&mut out, "connect4_rate_limited_requests_total", "counter", "Connections turned away because their client sent too many requests.");
+        writeln!(out, "connect4_rate_limited_requests_total {}", self.limited.load(Ordering::Relaxed)).unwrap();
+
+        header(&mut out, "connect4_request_duration_seconds", "histogram", "Time to answer a request, by route.");
+        for (route, histogram) in self.latency.lock().unwrap().iter() {
+            histogram.render(&mut out, "connect4_request_duration_seconds", &format!("route=\"{}\"", route));
+        }
+
+        header(&mut out, "connect4_search_nodes", "histogram", "Nodes searched per solved position.");
+        self.nodes.lock().unwrap().render(&mut out, "connect4_search_nodes", "");
+
+        header(&mut out, "connect4_searches_cancelled_total", "counter", "Searches stopped because the client went away.");
+        writeln!(out, "connect4_searches_cancelled_total {}", self.cancelled.load(Ordering::Relaxed)).unwrap();
+
+        header(&mut out, "connect4_searches_timed_out_total", "counter", "Searches stopped by the request budget, answered with the best move so far.");
+        writeln!(out, "connect4_searches_timed_out_total {}", self.timed_out.load(Ordering::Relaxed)).unwrap();
+
+        let search = *self.search.lock().unwrap();
+        for (name, help, value) in [
+            ("connect4_search_nodes_total", "Nodes searched.", search.nodes),
+            ("connect4_tt_probes_total", "Transposition table lookups.", search.tt_probes),
+            ("connect4_tt_hits_total", "Transposition table lookups that found an entry.", search.tt_hits),
+            ("connect4_book_hits_total", "Positions scored by the opening book.", search.book_hits),
+            ("connect4_cache_hits_total", "Requests answered from the solved position cache.", cache.hits),
+            ("connect4_cache_misses_total", "Requests the solved position cache could not answer.", cache.misses),
+        ] {
+            header(&mut out, name, "counter", help);
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        for (name, help, value) in [
+            ("connect4_tt_hit_ratio", "Share of transposition table lookups that found an entry.", search.tt_hit_rate()),
+            ("connect4_cache_hit_ratio", "Share of requests answered from the solved position cache.", cache.hit_rate()),
+            ("connect4_cache_entries", "Positions in the solved position cache.", cache.entries as f64),
+            ("connect4_sessions", "Games in progress.", sessions as f64),
+        ] {
+            header(&mut out, name, "gauge", help);
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_request("solve", 200, Duration::from_millis(20));
+        metrics.observe_request("solve", 200, Duration::from_millis(2));
+        metrics.observe_request("games", 404, Duration::from_millis(1));
+        metrics.observe_search(&SearchStats { nodes: 5000, tt_probes: 4, tt_hits: 1, ..Default::default() });
+
+        let out = metrics.render(CacheStats::default(), 2);
+        assert!(out.contains("connect4_requests_total{route=\"solve\",status=\"200\"} 2\n"));
+        assert!(out.contains("connect4_requests_total{route=\"games\",status=\"404\"} 1\n"));
+        assert!(out.contains("connect4_request_duration_seconds_bucket{route=\"solve\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("connect4_request_duration_seconds_bucket{route=\"solve\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("connect4_request_duration_seconds_count{route=\"solve\"} 2\n"));
+        assert!(out.contains("connect4_search_nodes_bucket{le=\"10000\"} 1\n"));
+        assert!(out.contains("connect4_search_nodes_count 1\n"));
+        assert!(out.contains("connect4_tt_hit_ratio 0.25\n"));
+        assert!(out.contains("connect4_sessions 2\n"));
+    }
+}
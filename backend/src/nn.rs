@@ -0,0 +1,209 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::board::{Board, HEIGHT, WIDTH};
+
+const MAGIC: &[u8; 4] = b"C4NN";
+const VERSION: u32 = 1;
+
+/// Cells of the board, each giving one input for the side to move's tiles
+/// and one for the opponent's.
+pub const CELLS: usize = WIDTH * HEIGHT;
+/// Inputs of the first layer: the side to move's tiles then the opponent's,
+/// each by column from the left and then by row from the bottom.
+pub const INPUTS: usize = 2 * CELLS;
+
+/// Moves from the start up to which an agent orders moves with the network
+/// by default, deeper nodes being too many to evaluate every child of.
+pub const DEFAULT_ORDERING_PLY: usize = 24;
+
+/// A fully connected layer, with `weights[output][input]` as stored in files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    pub weights: Vec<Vec<f32>>,
+    pub biases: Vec<f32>,
+}
+
+/// A small fully connected network estimating the side to move's score of
+/// a position, with ReLU between layers and a linear output.
+///
+/// Weights files are little endian: the magic `C4NN`, the version (1) and
+/// the number of layers as `u32`, then for each layer its inputs and
+/// outputs as `u32` followed by its `f32` weights, one output's row after
+/// another, and `f32` biases. The first layer takes `INPUTS` inputs, each
+/// layer as many as the previous one outputs, and the last outputs one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    layers: Vec<Dense>,
+}
+
+// a layer with its weights stored by input, so inputs of 0 can be skipped
+#[derive(Clone, Debug, PartialEq)]
+struct Dense {
+    outputs: usize,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl Network {
+    pub fn new(layers: Vec<Layer>) -> std::io::Result<Network> {
+        let mut inputs = INPUTS;
+        let mut dense = Vec::with_capacity(layers.len());
+        for (i, layer) in layers.into_iter().enumerate() {
+            let outputs = layer.biases.len();
+            if outputs == 0 || layer.weights.len() != outputs || layer.weights.iter().any(|row| row.len() != inputs) {
+                return Err(invalid(format!("layer {} does not take {} inputs to {} outputs", i, inputs, outputs)));
+            }
+
+            let mut weights = vec![0.0; inputs * outputs];
+            for (output, row) in layer.weights.iter().enumerate() {
+                for (input, weight) in row.iter().enumerate() { weights[input * outputs + output] = *weight; }
+            }
+
+            dense.push(Dense { outputs, weights, biases: layer.biases });
+            inputs = outputs;
+        }
+
+        if inputs != 1 || dense.is_empty() { return Err(invalid("the last layer must have one output".to_string())); }
+        Ok(Network { layers: dense })
+    }
+
+    /// Reads a weights file, see `Network`.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Network> {
+        Network::read(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read(input: &mut impl Read) -> std::io::Result<Network> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(invalid("not a network weights file".to_string())); }
+
+        let version = read_u32(input)?;
+        if version != VERSION { return Err(invalid(format!("unsupported weights version {}", version))); }
+
+        let num_layers = read_u32(input)?;
+        let mut layers = Vec::new();
+        for _ in 0..num_layers {
+            let inputs = read_u32(input)? as usize;
+            let outputs = read_u32(input)? as usize;
+            // refuse sizes no small network has before allocating for them
+            if inputs > 4096 || outputs > 4096 { return Err(invalid(format!("layer of {}x{} is too large", outputs, inputs))); }
+
+            let weights = (0..outputs).map(|_| read_f32s(input, inputs)).collect::<std::io::Result<_>>()?;
+            let biases = read_f32s(input, outputs)?;
+            layers.push(Layer { weights, biases });
+        }
+
+        Network::new(layers)
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(self.layers.len() as u32).to_le_bytes())?;
+        for layer in self.layers.iter() {
+            let inputs = layer.weights.len() / layer.outputs;
+            out.write_all(&(inputs as u32).to_le_bytes())?;
+            out.write_all(&(layer.outputs as u32).to_le_bytes())?;
+            for output in 0..layer.outputs {
+                for input in 0..inputs { out.write_all(&layer.weights[input * layer.outputs + output].to_le_bytes())?; }
+            }
+            for bias in layer.biases.iter() { out.write_all(&bias.to_le_bytes())?; }
+        }
+        Ok(())
+    }
+
+    /// The estimated score of `board` for the side to move, in the units of
+    /// solved scores.
+    pub fn evaluate(&self, board: &Board) -> f32 {
+        // the inputs are 0 or 1, so the first layer only adds up the weights
+        // of occupied cells
+        let first = &self.layers[0];
+        let mut values = first.biases.clone();
+        let (player_mask, tile_mask) = board.masks();
+        for (offset, mut mask) in [(0, player_mask), (CELLS, player_mask ^ tile_mask)] {
+            while mask != 0 {
+                let bit = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                let input = offset + bit / (HEIGHT + 1) * HEIGHT + bit % (HEIGHT + 1);
+                for (value, weight) in values.iter_mut().zip(first.weights[input * first.outputs..].iter()) { *value += weight; }
+            }
+        }
+
+        for layer in self.layers[1..].iter() {
+            let mut outputs = layer.biases.clone();
+            for (input, value) in values.iter().enumerate() {
+                if *value <= 0.0 { continue; }
+                for (output, weight) in outputs.iter_mut().zip(layer.weights[input * layer.outputs..].iter()) { *output += value * weight; }
+            }
+            values = outputs;
+        }
+
+        values[0]
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32s(input: &mut impl Read, n: usize) -> std::io::Result<Vec<f32>> {
+    let mut bytes = vec![0; n * 4];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes.chunks_exact(4).map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())).collect())
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // counts central tiles, the side to move's up and the opponent's down
+    fn center_network() -> Network {
+        let centrality = |input: usize| 4.0 - (input % CELLS / HEIGHT).abs_diff(3) as f32;
+        let hidden = vec![
+            (0..INPUTS).map(|input| if input < CELLS { centrality(input) } else { 0.0 }).collect(),
+            (0..INPUTS).map(|input| if input < CELLS { 0.0 } else { centrality(input) }).collect(),
+        ];
+
+        Network::new(vec![
+            Layer { weights: hidden, biases: vec![0.0, 0.0] },
+            Layer { weights: vec![vec![1.0, -1.0]], biases: vec![0.0] },
+        ]).unwrap()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let network = center_network();
+        assert_eq!(network.evaluate(&Board::new()), 0.0);
+
+        // the side to move has the center against a corner
+        assert_eq!(network.evaluate(&Board::from_position("41").unwrap()), 3.0);
+        assert_eq!(network.evaluate(&Board::from_position("4").unwrap()), -4.0);
+        assert_eq!(network.evaluate(&Board::from_position("4447").unwrap()), 8.0 - 5.0);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let network = center_network();
+        let mut bytes = Vec::new();
+        network.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 12 + 8 + 4 * (2 * INPUTS + 2) + 8 + 4 * 3);
+        assert_eq!(Network::read(&mut bytes.as_slice()).unwrap(), network);
+
+        assert!(Network::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(Network::read(&mut &b"C4NX"[..]).is_err());
+
+        // the second layer takes two inputs, not one
+        let err = Network::new(vec![
+            Layer { weights: vec![vec![0.0; INPUTS]], biases: vec![0.0] },
+            Layer { weights: vec![vec![0.0, 0.0]], biases: vec![0.0] },
+        ]).unwrap_err();
+        assert_eq!(err.to_string(), "layer 1 does not take 1 inputs to 1 outputs");
+    }
+}
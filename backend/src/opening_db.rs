@@ -1,18 +1,39 @@
 use std::collections::HashSet;
 use std::fs::{OpenOptions, File};
-use std::io::{stdout, Write, Read, BufWriter, BufReader};
+use std::io::{stdout, Write, Read, BufWriter, BufReader, Error, ErrorKind};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use rayon::prelude::*;
 
 use crate::board::Board;
 use crate::agent::Agent;
-use crate::transposition::{TranspositionTable, TABLE_SIZE};
+use crate::transposition::{ScoreTable, TABLE_SIZE};
 use serde::{Serialize, Deserialize};
 
+/// A handle to the opening database in use, shared by agents and swappable
+/// at runtime. Clones share the same database.
+#[derive(Clone, Default)]
+pub struct SharedBook(Arc<RwLock<Option<Arc<OpeningDatabase>>>>);
+
+impl SharedBook {
+    pub fn new(db: Option<Arc<OpeningDatabase>>) -> Self {
+        SharedBook(Arc::new(RwLock::new(db)))
+    }
+
+    pub fn get(&self) -> Option<Arc<OpeningDatabase>> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replaces the database for searches started from now on, returning
+    /// the previous one.
+    pub fn swap(&self, db: Option<Arc<OpeningDatabase>>) -> Option<Arc<OpeningDatabase>> {
+        std::mem::replace(&mut *self.0.write().unwrap(), db)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct OpeningDatabase {
-    table: TranspositionTable,
+    table: ScoreTable,
     depth: usize,
 }
 
@@ -29,14 +50,14 @@ impl OpeningDatabase {
         let stdout = Arc::new(Mutex::new(stdout()));
         let start_time = std::time::Instant::now();
 
-        let db = Self::load("compiled_db.bin".to_string());
+        let db = Arc::new(Self::load("compiled_db.bin".to_string()));
 
         print!("Calculating board scores [0.0%]  ETA: --:--  0/{}", boards.len());
         stdout.lock().unwrap().flush().unwrap();
 
         let map_func = |board: &Board| {
-            let mut agent = Agent::new(Some(&db));
-            let (score, num_visited) = agent.best_score(*board);
+            let mut agent = Agent::new(Some(db.clone()));
+            let (score, stats) = agent.best_score(*board).unwrap();
             let entry = board.hash() << 8 | (score as u64);
 
             cur.fetch_add(1, Ordering::Relaxed);
@@ -48,18 +69,17 @@ impl OpeningDatabase {
             print!("\rCalculating board scores [{:.1}%]  ETA: {}  {}/{}", (cur_ as f32)/(total as f32)*100.0, eta, cur_, total);
             stdout.lock().unwrap().flush().unwrap();
 
-            (entry, num_visited)
+            (entry, stats.nodes)
         };
 
         let mut board_scores = boards.par_iter().map(map_func).collect::<Vec<(u64, u64)>>();
-        board_scores.sort_by(|a, b| a.1.cmp(&b.1));
+        board_scores.sort_by_key(|a| a.1);
         println!();
 
         println!("Writing to file: {}", path);
 
         let mut file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(path)
             .unwrap();
@@ -68,15 +88,15 @@ impl OpeningDatabase {
         file.write_all(&depth.to_be_bytes()).unwrap();
 
         // write book_vec to file
-        for i in 0..board_scores.len() {
-            file.write_all(&board_scores[i].0.to_be_bytes()).unwrap();
+        for (entry, _) in board_scores.iter() {
+            file.write_all(&entry.to_be_bytes()).unwrap();
         }
     }
 
     pub fn compile(dest_file: String, src_files: Vec<String>) {
         // let db = Self::load_raw(src_files);
 
-        let mut table = TranspositionTable::new();
+        let mut table = ScoreTable::new();
         let mut depth = 0;
         let mut entries: Vec<u64> = Vec::new();
 
@@ -108,21 +128,37 @@ impl OpeningDatabase {
         println!("Table unique hashes: {:.2}%", table_fullness);
 
         println!("Writing to file: {}", dest_file);
-        let db = Self { table: table, depth: depth };
+        let db = Self { table, depth };
         let f = BufWriter::new(File::create(dest_file).unwrap());
         bincode::serialize_into(f, &db).unwrap();
         println!("Done");
     }
 
     pub fn load(src_file: String) -> Self {
-        let f = BufReader::new(File::open(src_file).unwrap());
-        bincode::deserialize_from(f).unwrap()
+        Self::open(&src_file).unwrap()
+    }
+
+    pub fn open(src_file: &str) -> std::io::Result<Self> {
+        let f = BufReader::new(File::open(src_file)?);
+        bincode::deserialize_from(f).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Positions with up to this many moves played are in the book.
+    pub fn depth(&self) -> usize {
+        self.depth
     }
 
     pub fn get(&self, key: u64, depth: usize) -> Option<i8> {
         if depth <= self.depth { self.table.get(key) } else { None }
     }
 
+    /// Every position in the book with its score.
+    pub fn positions(&self) -> Vec<(Board, i8)> {
+        self.table.entries()
+            .filter_map(|(key, score)| Board::from_key(key).ok().filter(|board| board.num_actions() <= self.depth).map(|board| (board, score)))
+            .collect()
+    }
+
     fn gen_positions(board: Board, depth: u32, boards: &mut HashSet<Board>) {
         if board.has_winning_action() { return; }
         if board.num_actions() >= 42 { return; }
@@ -134,10 +170,48 @@ impl OpeningDatabase {
 
         for col in 0..7 {
             if board.is_valid_col(col) {
-                let mut child = board.clone();
+                let mut child = board;
                 child.play_col(col);
                 OpeningDatabase::gen_positions(child, depth, boards);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_book() {
+        // the book claims a made up score for a position it hasn't searched
+        let booked = Board::from_position("4455").unwrap();
+        let mut table = ScoreTable::new();
+        table.set(booked.hash(), -3);
+        let db = Arc::new(OpeningDatabase { table, depth: 42 });
+
+        let book = SharedBook::default();
+        let mut agent = Agent::new(None).with_book(book.clone());
+        let (_, stats) = agent.best_score(Board::from_position("2252576253462244111563365343671351441").unwrap()).unwrap();
+        assert_eq!(stats.book_hits, 0);
+
+        // agents pick up the new book at their next search
+        assert!(book.swap(Some(db)).is_none());
+        let (score, stats) = agent.best_score(booked).unwrap();
+        assert_eq!(score, -3);
+        assert!(stats.book_hits > 0);
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut table = ScoreTable::with_size(0);
+        for (position, score) in [("4455", -3), ("1", 2), ("44444", 1)] {
+            table.set(Board::from_position(position).unwrap().hash(), score);
+        }
+
+        let db = OpeningDatabase { table, depth: 4 };
+        let mut positions = db.positions();
+        positions.sort_by_key(|(board, _)| board.num_actions());
+        assert_eq!(positions, vec![(Board::from_position("1").unwrap(), 2), (Board::from_position("4455").unwrap(), -3)]);
+    }
+}
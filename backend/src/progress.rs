@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::stats::serialize_micros;
+
+/// A snapshot of a running search.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Progress {
+    /// Column whose reply is being searched, when choosing a move.
+    pub col: Option<u8>,
+    /// Bounds on the score proven so far.
+    pub min: i8,
+    pub max: i8,
+    pub nodes: u64,
+    #[serde(rename = "elapsed_us", serialize_with = "serialize_micros")]
+    pub elapsed: Duration,
+}
+
+/// Receives progress while an agent searches. Called from the searching
+/// thread, so implementations should return quickly.
+pub trait SearchObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+
+    /// Called once the score of playing `col` is proven while choosing a move.
+    fn on_column(&self, _col: u8, _score: i8) {}
+}
+
+impl<F: Fn(&Progress) + Send + Sync> SearchObserver for F {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Caps on the work of one search, after which it stops with `Cancelled`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SearchLimits {
+    /// Nodes searched, checked every few thousand nodes.
+    pub nodes: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl SearchLimits {
+    /// What is left of the limits after searching `nodes` nodes for `elapsed`.
+    pub fn remaining(&self, nodes: u64, elapsed: Duration) -> SearchLimits {
+        SearchLimits {
+            nodes: self.nodes.map(|limit| limit.saturating_sub(nodes)),
+            time: self.time.map(|limit| limit.saturating_sub(elapsed)),
+        }
+    }
+
+    /// Nothing is left of one of the limits.
+    pub fn is_spent(&self) -> bool {
+        self.nodes == Some(0) || self.time == Some(Duration::ZERO)
+    }
+
+    /// The tighter of each limit.
+    pub fn min(&self, other: &SearchLimits) -> SearchLimits {
+        SearchLimits {
+            nodes: self.nodes.into_iter().chain(other.nodes).min(),
+            time: self.time.into_iter().chain(other.time).min(),
+        }
+    }
+}
+
+/// Stops a search from another thread. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
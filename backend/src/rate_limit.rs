@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// clients tracked at most, the least recently seen is forgotten for a new one
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    last_use: u64,
+}
+
+/// Allows each client address `rate` requests per second on average, in
+/// bursts of up to `burst`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    clients: HashMap<IpAddr, Bucket>,
+    // last use -> client, least recently seen first
+    recency: BTreeMap<u64, IpAddr>,
+    clock: u64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter { rate, burst: burst.max(1.0), clients: HashMap::new(), recency: BTreeMap::new(), clock: 0 }
+    }
+
+    /// Takes a request from the client's allowance, or tells how long until
+    /// it has one again.
+    pub fn check(&mut self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let rate = self.rate;
+        let bucket = self.bucket(client, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Takes `requests` more from the client's allowance, for a request that
+    /// costs more than one. The client may overdraw it, and is then refused
+    /// until it has refilled.
+    pub fn charge(&mut self, client: IpAddr, requests: f64, now: Instant) {
+        self.bucket(client, now).tokens -= requests;
+    }
+
+    // the client's bucket, refilled up to now and marked as the most recently used
+    fn bucket(&mut self, client: IpAddr, now: Instant) -> &mut Bucket {
+        if self.clients.len() >= MAX_CLIENTS && !self.clients.contains_key(&client) {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.clients.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        let (rate, burst, clock) = (self.rate, self.burst, self.clock);
+        let bucket = self.clients.entry(client).or_insert(Bucket { tokens: burst, updated: now, last_use: clock });
+        self.recency.remove(&bucket.last_use);
+        self.recency.insert(clock, client);
+
+        bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        bucket.last_use = clock;
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2.0, 3.0);
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        for _ in 0..3 { assert!(limiter.check(a, start).is_ok()); }
+        assert_eq!(limiter.check(a, start), Err(Duration::from_millis(500)));
+        assert!(limiter.check(b, start).is_ok());
+
+        // tokens refill at the rate, up to the burst
+        assert!(limiter.check(a, start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.check(a, start + Duration::from_millis(600)).is_err());
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 { assert!(limiter.check(a, later).is_ok()); }
+        assert!(limiter.check(a, later).is_err());
+
+        // an expensive request is paid back before the next is allowed
+        limiter.charge(b, 4.0, later);
+        assert_eq!(limiter.check(b, later), Err(Duration::from_millis(1000)));
+        assert!(limiter.check(b, later + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let mut limiter = RateLimiter::new(1.0, 1.0);
+        let now = Instant::now();
+        let client = |i: usize| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, (i >> 16) as u16, i as u16]);
+
+        // clients still spending their allowance are forgotten all the same,
+        // least recently seen first
+        limiter.check(client(0), now).unwrap();
+        for i in 1..MAX_CLIENTS * 2 {
+            limiter.check(client(i), now).unwrap();
+            // not yet forgotten, with its allowance still spent
+            if i == MAX_CLIENTS / 2 { assert!(limiter.check(client(0), now).is_err()); }
+            assert!(limiter.clients.len() <= MAX_CLIENTS);
+        }
+        assert_eq!((limiter.clients.len(), limiter.recency.len()), (MAX_CLIENTS, MAX_CLIENTS));
+        assert!(!limiter.clients.contains_key(&client(1)));
+        assert!(limiter.clients.contains_key(&client(MAX_CLIENTS * 2 - 1)));
+    }
+}
@@ -0,0 +1,497 @@
+use std::fmt;
+use std::time::Instant;
+use schemars::JsonSchema;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::agent::{self, Agent, Cancelled};
+use crate::board::{Board, Player, PositionError, WIDTH};
+use crate::progress::SearchLimits;
+use crate::stats::SearchStats;
+
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+/// The board could not be read or no legal game reaches it.
+pub const INVALID_POSITION: i32 = -32000;
+/// The game on the board is already over.
+pub const GAME_OVER: i32 = -32001;
+/// The search ran out of budget, the error data is a `Stopped`.
+pub const SEARCH_STOPPED: i32 = -32002;
+
+/// Most boards `batch_solve` takes at once.
+pub const MAX_BATCH_BOARDS: usize = 16;
+/// Most calls a batch of requests may hold.
+pub const MAX_BATCH_CALLS: usize = 16;
+
+// errors the solving methods may answer with, for the schema
+const SOLVE_ERRORS: [(i32, &str); 4] = [
+    (INVALID_PARAMS, "Invalid params"),
+    (INVALID_POSITION, "Invalid position"),
+    (GAME_OVER, "Game is over"),
+    (SEARCH_STOPPED, "Search ran out of budget"),
+];
+
+/// A board, either as the columns played from the start numbered from 1,
+/// `{"moves": "4455"}`, or as a grid as read by `Board::from_grid`,
+/// `{"grid": "......./......./......./......./......./...x... o"}`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Moves(String),
+    Grid(String),
+}
+
+impl Position {
+    pub fn board(&self) -> Result<Board, PositionError> {
+        match self {
+            Position::Moves(moves) => Board::from_position(moves),
+            Position::Grid(grid) => Board::from_grid(grid),
+        }
+    }
+}
+
+/// Parameters of `solve` and `analyze`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SolveParams {
+    pub board: Position,
+    /// Also find the principal variation, which takes a search per move
+    /// within what the first search left of the budget.
+    #[serde(default)]
+    pub pv: bool,
+}
+
+/// Parameters of `batch_solve`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BatchParams {
+    /// At most `MAX_BATCH_BOARDS` boards, solved in turn within one budget.
+    pub boards: Vec<Position>,
+    #[serde(default)]
+    pub pv: bool,
+}
+
+/// A board as solved.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+pub struct BoardState {
+    /// Moves reaching the board, numbered from 1.
+    pub moves: Option<String>,
+    pub grid: String,
+    pub side_to_move: Player,
+    pub num_moves: usize,
+}
+
+impl BoardState {
+    pub fn new(board: &Board) -> BoardState {
+        BoardState {
+            moves: board.to_position(),
+            grid: board.to_grid(),
+            side_to_move: board.side_to_move(),
+            num_moves: board.num_actions(),
+        }
+    }
+}
+
+/// The answer to `solve` and `analyze`. Columns are numbered from 0, and
+/// scores are the side to move's: positive wins, higher the sooner, 0 draws
+/// and negative loses.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct Solution {
+    pub board: BoardState,
+    /// The best move.
+    pub col: u8,
+    pub score: i8,
+    /// Score of every column, null for full ones, from `analyze` only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scores: Option<[Option<i8>; WIDTH]>,
+    /// Best play for both sides to the end of the game, starting with `col`,
+    /// when asked for and found within the budget.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pv: Option<Vec<u8>>,
+    pub stats: SearchStats,
+}
+
+/// What a search had found when it ran out of budget, the data of a
+/// `SEARCH_STOPPED` error.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Stopped {
+    /// The best move found so far.
+    pub col: u8,
+    /// Bounds proven on the score.
+    pub min: i8,
+    pub max: i8,
+    /// Scores of the columns solved before stopping.
+    pub scores: [Option<i8>; WIDTH],
+    pub nodes: u64,
+}
+
+/// A JSON-RPC error object.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl ToString) -> RpcError {
+        RpcError { code, message: message.to_string(), data: None }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// One answer of `batch_solve`, in the order the boards were given.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchItem {
+    Result(Solution),
+    Error(RpcError),
+}
+
+// what is left of the agent's limits for the searches of one request, which
+// all share them
+struct Budget {
+    limits: SearchLimits,
+    start: Instant,
+    nodes: u64,
+}
+
+impl Budget {
+    fn remaining(&self) -> SearchLimits {
+        self.limits.remaining(self.nodes, self.start.elapsed())
+    }
+}
+
+// runs `f` with a budget of the agent's limits, which are restored after
+fn with_budget<R>(agent: &mut Agent, f: impl FnOnce(&mut Agent, &mut Budget) -> R) -> R {
+    let limits = agent.limits();
+    let mut budget = Budget { limits, start: Instant::now(), nodes: 0 };
+    let result = f(agent, &mut budget);
+    agent.set_limits(limits);
+    result
+}
+
+/// The best move on a board and its score.
+pub fn solve(agent: &mut Agent, params: &SolveParams) -> Result<Solution, RpcError> {
+    with_budget(agent, |agent, budget| solution(agent, budget, params, false))
+}
+
+/// Scores every column of a board.
+pub fn analyze(agent: &mut Agent, params: &SolveParams) -> Result<Solution, RpcError> {
+    with_budget(agent, |agent, budget| solution(agent, budget, params, true))
+}
+
+/// Solves each board in turn, within the agent's limits for all of them,
+/// answering with an error for the boards that could not be solved rather
+/// than failing the whole batch.
+pub fn batch_solve(agent: &mut Agent, params: &BatchParams) -> Result<Vec<BatchItem>, RpcError> {
+    with_budget(agent, |agent, budget| batch_items(agent, budget, params))
+}
+
+fn batch_items(agent: &mut Agent, budget: &mut Budget, params: &BatchParams) -> Result<Vec<BatchItem>, RpcError> {
+    if params.boards.len() > MAX_BATCH_BOARDS {
+        return Err(RpcError::new(INVALID_PARAMS, format!("at most {} boards per batch", MAX_BATCH_BOARDS)));
+    }
+
+    Ok(params.boards.iter().map(|board| {
+        match solution(agent, budget, &SolveParams { board: board.clone(), pv: params.pv }, false) {
+            Ok(solution) => BatchItem::Result(solution),
+            Err(e) => BatchItem::Error(e),
+        }
+    }).collect())
+}
+
+// searches within what is left of `budget`, spending the nodes searched
+fn solution(agent: &mut Agent, budget: &mut Budget, params: &SolveParams, analyze: bool) -> Result<Solution, RpcError> {
+    let board = params.board.board().map_err(|e| RpcError::new(INVALID_POSITION, e))?;
+    if board.status().is_over() { return Err(RpcError::new(GAME_OVER, "game is over")); }
+
+    agent.set_limits(budget.remaining());
+    let result = if analyze {
+        agent.column_scores(board).map(|(scores, stats)| {
+            let (col, score) = agent::best_column(&scores).unwrap();
+            (col, score, Some(scores), stats)
+        })
+    } else {
+        agent.best_col(board).map(|(col, score, stats)| (col, score, None, stats))
+    };
+    let (col, score, scores, stats) = result.map_err(|cancelled| {
+        budget.nodes += cancelled.nodes;
+        stopped(&board, &cancelled)
+    })?;
+    budget.nodes += stats.nodes;
+
+    let pv = if !params.pv { None } else {
+        match agent.principal_variation(board, budget.remaining()) {
+            Ok((pv, pv_stats)) => {
+                budget.nodes += pv_stats.nodes;
+                Some(pv)
+            },
+            Err(cancelled) => {
+                budget.nodes += cancelled.nodes;
+                None
+            },
+        }
+    };
+    Ok(Solution { board: BoardState::new(&board), col, score, scores, pv, stats })
+}
+
+fn stopped(board: &Board, cancelled: &Cancelled) -> RpcError {
+    let data = Stopped {
+        col: agent::best_so_far(board, cancelled),
+        min: cancelled.min,
+        max: cancelled.max,
+        scores: cancelled.scores,
+        nodes: cancelled.nodes,
+    };
+    RpcError { code: SEARCH_STOPPED, message: "search ran out of budget".to_string(), data: Some(serde_json::to_value(data).unwrap()) }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Answers a JSON-RPC 2.0 request, or a batch of up to `MAX_BATCH_CALLS`
+/// of them, solving with `agent` within its limits for the whole request.
+/// Returns `None` when there is nothing to answer, as for notifications.
+pub fn handle(agent: &mut Agent, request: &str) -> Option<String> {
+    let response = match serde_json::from_str::<Value>(request) {
+        Err(e) => Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e)))),
+        Ok(Value::Array(calls)) if calls.is_empty() => Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, "empty batch")))),
+        Ok(Value::Array(calls)) if calls.len() > MAX_BATCH_CALLS => {
+            Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, format!("at most {} calls per batch", MAX_BATCH_CALLS)))))
+        },
+        Ok(Value::Array(calls)) => with_budget(agent, |agent, budget| {
+            let responses: Vec<Value> = calls.into_iter().filter_map(|request| call(agent, budget, request)).collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }),
+        Ok(request) => with_budget(agent, |agent, budget| call(agent, budget, request)),
+    };
+
+    response.map(|response| response.to_string())
+}
+
+/// How many boards answering `request` may search: one per `solve` or
+/// `analyze` call and one per board of a `batch_solve`, for charging it
+/// against a rate limit before answering it with `handle`.
+pub fn searches(request: &str) -> usize {
+    let calls = match serde_json::from_str::<Value>(request) {
+        Ok(Value::Array(calls)) => calls.into_iter().take(MAX_BATCH_CALLS).collect(),
+        Ok(request) => vec![request],
+        Err(_) => Vec::new(),
+    };
+
+    calls.iter().map(|call| match call.get("method").and_then(Value::as_str) {
+        Some("solve" | "analyze") => 1,
+        Some("batch_solve") => call.pointer("/params/boards").and_then(Value::as_array).map_or(0, |boards| boards.len().min(MAX_BATCH_BOARDS)),
+        _ => 0,
+    }).sum()
+}
+
+// None for notifications, which have no id
+fn call(agent: &mut Agent, budget: &mut Budget, request: Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let request: Request = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => return Some(response(id.unwrap_or(Value::Null), Err(RpcError::new(INVALID_REQUEST, e)))),
+    };
+    if request.jsonrpc != "2.0" {
+        return Some(response(id.unwrap_or(Value::Null), Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))));
+    }
+
+    let result = dispatch(agent, budget, &request.method, request.params);
+    id.map(|id| response(id, result))
+}
+
+fn dispatch(agent: &mut Agent, budget: &mut Budget, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "solve" => solution(agent, budget, &parse_params(params)?, false).map(|solution| serde_json::to_value(solution).unwrap()),
+        "analyze" => solution(agent, budget, &parse_params(params)?, true).map(|solution| serde_json::to_value(solution).unwrap()),
+        "batch_solve" => batch_items(agent, budget, &parse_params(params)?).map(|items| serde_json::to_value(items).unwrap()),
+        "rpc.discover" => Ok(schema()),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+/// An OpenRPC document describing every method, with JSON schemas derived
+/// from the types above. `rpc.discover` answers with it.
+pub fn schema() -> Value {
+    let mut gen = SchemaSettings::draft07().with(|settings| settings.definitions_path = "#/components/schemas/".to_string()).into_generator();
+    let errors: Vec<Value> = SOLVE_ERRORS.iter().map(|(code, message)| json!({ "code": code, "message": message })).collect();
+
+    let methods = vec![
+        method::<SolveParams, Solution>(&mut gen, "solve", "The best move on a board and its score.", &errors),
+        method::<SolveParams, Solution>(&mut gen, "analyze", "Scores every column of a board.", &errors),
+        method::<BatchParams, Vec<BatchItem>>(&mut gen, "batch_solve", "Solves many boards, answering for each with a result or an error.", &errors[..1]),
+    ];
+    // referenced only from error data
+    gen.subschema_for::<Stopped>();
+    gen.subschema_for::<RpcError>();
+
+    json!({
+        "openrpc": "1.2.6",
+        "info": { "title": "connect4", "version": env!("CARGO_PKG_VERSION") },
+        "methods": methods,
+        "components": { "schemas": gen.take_definitions() },
+    })
+}
+
+// params are passed by name, one per field of `P`
+fn method<P: JsonSchema, R: JsonSchema>(gen: &mut SchemaGenerator, name: &str, summary: &str, errors: &[Value]) -> Value {
+    let object = P::json_schema(gen).into_object().object.unwrap_or_default();
+    let params: Vec<Value> = object.properties.iter()
+        .map(|(param, schema)| json!({ "name": param, "required": object.required.contains(param), "schema": schema }))
+        .collect();
+
+    json!({
+        "name": name,
+        "summary": summary,
+        "paramStructure": "by-name",
+        "params": params,
+        "result": { "name": "result", "schema": gen.subschema_for::<R>() },
+        "errors": errors,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::progress::SearchLimits;
+
+    fn call(agent: &mut Agent, request: Value) -> Value {
+        serde_json::from_str(&handle(agent, &request.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut agent = Agent::new(None);
+
+        // x completes its bottom row in either of two columns
+        let response = call(&mut agent, json!({ "jsonrpc": "2.0", "method": "solve", "params": { "board": { "moves": "445566" }, "pv": true }, "id": 1 }));
+        assert_eq!(response["id"], 1);
+        assert_eq!((&response["result"]["col"], &response["result"]["score"]), (&json!(2), &json!(18)));
+        assert_eq!(response["result"]["pv"], json!([2]));
+        assert_eq!(response["result"]["board"]["side_to_move"], "first");
+        assert!(response["result"].get("scores").is_none());
+
+        let grid = Board::from_position("445566").unwrap().to_grid();
+        let response = call(&mut agent, json!({ "jsonrpc": "2.0", "method": "analyze", "params": { "board": { "grid": grid } }, "id": "a" }));
+        assert_eq!(response["result"]["scores"][6], 18);
+        let moves = response["result"]["board"]["moves"].as_str().unwrap();
+        assert_eq!(Board::from_position(moves).unwrap().to_grid(), grid);
+
+        let response = call(&mut agent, json!({ "jsonrpc": "2.0", "method": "batch_solve", "params": { "boards": [{ "moves": "445566" }, { "moves": "8" }, { "moves": "4455667" }] }, "id": 2 }));
+        let items = response["result"].as_array().unwrap();
+        assert_eq!(items[0]["result"]["col"], 2);
+        assert_eq!((&items[1]["error"]["code"], &items[2]["error"]["code"]), (&json!(INVALID_POSITION), &json!(GAME_OVER)));
+
+        let boards = vec![json!({ "moves": "445566" }); MAX_BATCH_BOARDS + 1];
+        let response = call(&mut agent, json!({ "jsonrpc": "2.0", "method": "batch_solve", "params": { "boards": boards }, "id": 2 }));
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+
+        // a batch of requests, where notifications get no answer
+        let response = call(&mut agent, json!([
+            { "jsonrpc": "2.0", "method": "solve", "params": { "board": { "moves": "445566" } } },
+            { "jsonrpc": "2.0", "method": "resign", "id": 3 },
+            { "jsonrpc": "2.0", "method": "solve", "params": { "moves": "4" }, "id": 4 },
+            { "jsonrpc": "1.0", "method": "solve", "id": 5 },
+        ]));
+        let codes: Vec<&Value> = response.as_array().unwrap().iter().map(|response| &response["error"]["code"]).collect();
+        assert_eq!(codes, vec![&json!(METHOD_NOT_FOUND), &json!(INVALID_PARAMS), &json!(INVALID_REQUEST)]);
+        assert_eq!(handle(&mut agent, r#"{"jsonrpc": "2.0", "method": "solve", "params": {"board": {"moves": "445566"}}}"#), None);
+        assert_eq!(call(&mut agent, json!([]))["error"]["code"], INVALID_REQUEST);
+
+        let response: Value = serde_json::from_str(&handle(&mut agent, "{\"jsonrpc\": ").unwrap()).unwrap();
+        assert_eq!((&response["error"]["code"], &response["id"]), (&json!(PARSE_ERROR), &Value::Null));
+
+        agent.set_limits(SearchLimits { nodes: Some(4096), time: None });
+        let response = call(&mut agent, json!({ "jsonrpc": "2.0", "method": "solve", "params": { "board": { "moves": "" } }, "id": 6 }));
+        let stopped: Stopped = serde_json::from_value(response["error"]["data"].clone()).unwrap();
+        assert_eq!(response["error"]["code"], SEARCH_STOPPED);
+        assert_eq!(stopped.col, 3);
+    }
+
+    #[test]
+    fn test_budget() {
+        let solve = |moves: &str| json!({ "jsonrpc": "2.0", "method": "solve", "params": { "board": { "moves": moves } }, "id": moves });
+        let limits = SearchLimits { nodes: Some(80_000), time: None };
+
+        // each board fits in the budget on its own, about 60k and 40k nodes
+        for moves in ["6614446666373154", "4642332434166445"] {
+            let mut agent = Agent::new(None);
+            agent.set_limits(limits);
+            assert!(call(&mut agent, solve(moves)).get("result").is_some());
+        }
+
+        // but not both in one request, whether as calls or boards of a batch
+        let mut agent = Agent::new(None);
+        agent.set_limits(limits);
+        let response = call(&mut agent, json!([solve("6614446666373154"), solve("4642332434166445")]));
+        assert!(response[0].get("result").is_some());
+        assert_eq!(response[1]["error"]["code"], SEARCH_STOPPED);
+        assert_eq!(agent.limits(), limits);
+
+        let mut agent = Agent::new(None);
+        agent.set_limits(limits);
+        let items = batch_solve(&mut agent, &BatchParams { boards: vec![Position::Moves("6614446666373154".to_string()), Position::Moves("4642332434166445".to_string())], pv: false }).unwrap();
+        assert!(matches!(items[..], [BatchItem::Result(_), BatchItem::Error(RpcError { code: SEARCH_STOPPED, .. })]));
+        assert_eq!(agent.limits(), limits);
+
+        let response = call(&mut agent, Value::Array(vec![solve("4"); MAX_BATCH_CALLS + 1]));
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_searches() {
+        let batch = json!({ "jsonrpc": "2.0", "method": "batch_solve", "params": { "boards": vec![json!({ "moves": "4" }); MAX_BATCH_BOARDS + 4] }, "id": 1 });
+        let analyze = json!({ "jsonrpc": "2.0", "method": "analyze", "params": { "board": { "moves": "4" } } });
+        let discover = json!({ "jsonrpc": "2.0", "method": "rpc.discover", "id": 2 });
+        assert_eq!(searches(&analyze.to_string()), 1);
+        assert_eq!(searches(&json!([batch, analyze, discover]).to_string()), MAX_BATCH_BOARDS + 1);
+        assert_eq!(searches(&Value::Array(vec![analyze; MAX_BATCH_CALLS + 4]).to_string()), MAX_BATCH_CALLS);
+        assert_eq!(searches("{\"jsonrpc\": "), 0);
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema();
+        let methods: Vec<&Value> = schema["methods"].as_array().unwrap().iter().map(|method| &method["name"]).collect();
+        assert_eq!(methods, vec!["solve", "analyze", "batch_solve"]);
+
+        let params = &schema["methods"][0]["params"];
+        assert_eq!((&params[0]["name"], &params[0]["required"]), (&json!("board"), &json!(true)));
+        assert_eq!((&params[1]["name"], &params[1]["required"]), (&json!("pv"), &json!(false)));
+
+        let schemas = &schema["components"]["schemas"];
+        for name in ["Position", "Solution", "BoardState", "SearchStats", "Player", "BatchItem", "Stopped", "RpcError"] {
+            assert!(schemas.get(name).is_some(), "missing {}", name);
+        }
+        assert_eq!(schemas["Solution"]["properties"]["stats"]["$ref"], "#/components/schemas/SearchStats");
+        assert!(schemas["SearchStats"]["properties"].get("elapsed_us").is_some());
+    }
+}
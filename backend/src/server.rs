@@ -0,0 +1,919 @@
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, trace, warn};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use lib::analysis::Analysis;
+use lib::board::{Board, Player, HEIGHT, WIDTH};
+use lib::cache::{CacheStats, ColumnScores, SolvedCache};
+use lib::progress::{CancellationToken, Progress, SearchLimits, SearchObserver};
+use lib::agent::{self, Agent, Cancelled};
+use lib::opening_db::{OpeningDatabase, SharedBook};
+use lib::history::{GameHistory, GameRecord};
+use lib::batch::{self, AgentPool, BatchFormat, BatchOptions, BatchPosition};
+use lib::rpc;
+use lib::session::{Difficulty, SessionError, SessionStore};
+use lib::stats::SearchStats;
+
+use crate::config::ServerOptions;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+
+// new entries between writes of the cache file
+const CACHE_SAVE_INTERVAL: usize = 100;
+
+// solved positions shared between requests, optionally persisted to a file
+struct ServerCache {
+    cache: Mutex<SolvedCache>,
+    path: Option<String>,
+    // entries inserted since the last snapshot taken to be saved, and the
+    // number of snapshots taken so far
+    unsaved: AtomicUsize,
+    snapshots: AtomicU64,
+    // the latest snapshot written, held while writing so an older snapshot
+    // never overwrites a newer one
+    saved: Mutex<u64>,
+}
+
+impl ServerCache {
+    fn open(path: Option<String>, size: usize) -> ServerCache {
+        let cache = match path {
+            Some(ref path) if std::path::Path::new(path).exists() => {
+                info!("Loading cache: {}", path);
+                SolvedCache::load(path, size).unwrap_or_else(|e| {
+                    warn!("Failed to load cache, starting empty: {}", e);
+                    SolvedCache::new(size)
+                })
+            },
+            _ => SolvedCache::new(size),
+        };
+        info!("Cache holds {} of {} positions", cache.len(), size);
+
+        ServerCache { cache: Mutex::new(cache), path, unsaved: AtomicUsize::new(0), snapshots: AtomicU64::new(0), saved: Mutex::new(0) }
+    }
+
+    fn get(&self, board: &Board) -> Option<ColumnScores> {
+        self.cache.lock().unwrap().get(board)
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    // copies the entries out while locked and writes them after, so requests
+    // using the cache don't wait on the disk
+    fn insert(&self, board: &Board, scores: ColumnScores) {
+        let snapshot = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(board, scores);
+            let unsaved = self.unsaved.fetch_add(1, Ordering::Relaxed) + 1;
+            (self.path.is_some() && unsaved >= CACHE_SAVE_INTERVAL).then(|| {
+                self.unsaved.store(0, Ordering::Relaxed);
+                (self.snapshots.fetch_add(1, Ordering::Relaxed) + 1, cache.entries())
+            })
+        };
+
+        if let (Some((number, entries)), Some(path)) = (snapshot, &self.path) {
+            let mut saved = self.saved.lock().unwrap();
+            if number < *saved { return; }
+            match SolvedCache::save_entries(&entries, path) {
+                Ok(()) => *saved = number,
+                Err(e) => error!("Failed to save cache to {}: {}", path, e),
+            }
+        }
+    }
+}
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BODY_SIZE: usize = 4096;
+// a list of positions to solve at /api/batch
+const MAX_BATCH_BODY_SIZE: usize = 256 * 1024;
+const MAX_BATCH_POSITIONS: usize = 1000;
+// batches are solved one at a time by a pool of agents with small tables,
+// and stop searching after a minute or about ten minutes' worth of nodes
+const BATCH_TABLE_SIZE: usize = 1 << 20;
+const BATCH_BUDGET: SearchLimits = SearchLimits { nodes: Some(10_000_000_000), time: Some(Duration::from_secs(60)) };
+// request line and headers together
+const MAX_HEADER_SIZE: u64 = 8192;
+// games idle for longer are forgotten
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_SESSIONS: usize = 10_000;
+const RECENT_GAMES: usize = 20;
+
+const BOOK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// loads the opening database once its file exists and reloads it whenever
+// it changes, e.g. after a new book is compiled, without interrupting
+// searches already running
+fn watch_book(path: String, book: SharedBook) {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    if modified(&path).is_none() { warn!("Database {} not found, waiting for it", path); }
+
+    thread::spawn(move || {
+        let mut last_modified = None;
+        loop {
+            let current = modified(&path);
+            if current.is_some() && current != last_modified {
+                last_modified = current;
+
+                // a book still being written fails to load and is retried once it changes again
+                match OpeningDatabase::open(&path) {
+                    Ok(db) => {
+                        info!("Loaded database: {} (depth {})", path, db.depth());
+                        book.swap(Some(Arc::new(db)));
+                    },
+                    Err(e) => warn!("Failed to load database {}: {}", path, e),
+                }
+            }
+
+            thread::sleep(BOOK_POLL_INTERVAL);
+        }
+    });
+}
+
+// state shared by every worker
+struct ServerState {
+    cache: ServerCache,
+    sessions: Mutex<SessionStore>,
+    history: Option<Mutex<GameHistory>>,
+    metrics: Metrics,
+    next_request: AtomicU64,
+    book: SharedBook,
+    book_path: Option<String>,
+    workers: usize,
+    // workers whose agent is ready to search
+    warm: AtomicUsize,
+    table_size: AtomicUsize,
+    cache_size: usize,
+    // None when clients are not rate limited
+    limiter: Option<Mutex<RateLimiter>>,
+    search_limits: SearchLimits,
+    batch_pool: Mutex<AgentPool>,
+}
+
+pub fn webserver(options: ServerOptions) {
+    let book = SharedBook::default();
+    if let Some(ref path) = options.book_path { watch_book(path.clone(), book.clone()); }
+
+    let state = Arc::new(ServerState {
+        cache: ServerCache::open(options.cache_path.clone(), options.cache_size),
+        sessions: Mutex::new(SessionStore::new(SESSION_TTL, MAX_SESSIONS)),
+        history: options.games_path.as_ref().map(|path| {
+            let history = GameHistory::open(path).unwrap_or_else(|e| {
+                error!("Failed to open game history {}: {}", path, e);
+                std::process::exit(1);
+            });
+            info!("Recording games to {} ({} so far)", path, history.len());
+            Mutex::new(history)
+        }),
+        metrics: Metrics::new(),
+        next_request: AtomicU64::new(1),
+        book: book.clone(),
+        book_path: options.book_path.clone(),
+        workers: options.workers,
+        warm: AtomicUsize::new(0),
+        table_size: AtomicUsize::new(0),
+        cache_size: options.cache_size,
+        limiter: (options.rate_limit > 0.0).then(|| Mutex::new(RateLimiter::new(options.rate_limit, options.burst as f64))),
+        search_limits: options.search_limits,
+        batch_pool: Mutex::new(AgentPool::new(&BatchOptions { threads: options.workers, table_size: BATCH_TABLE_SIZE, ..Default::default() }).with_book(book.clone())),
+    });
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(options.queue);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for i in 0..options.workers {
+        let receiver = receiver.clone();
+        let state = state.clone();
+        let book = book.clone();
+        let table_size = options.table_size;
+        let search_limits = options.search_limits;
+        thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
+            let mut agent = Agent::new(None).with_book(book).with_table_size(table_size);
+            agent.set_limits(search_limits);
+            state.table_size.store(agent.table_size(), Ordering::Relaxed);
+            state.warm.fetch_add(1, Ordering::Relaxed);
+
+            loop {
+                let stream = receiver.lock().unwrap().recv();
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+
+                // a failed request must not take its worker down with it
+                let result = panic::catch_unwind(AssertUnwindSafe(|| handle_connection(&mut agent, &state, stream)));
+                if result.is_err() {
+                    error!("Request handler panicked, worker recovered");
+                    agent.set_observer(None);
+                    agent.set_cancel_token(None);
+                    agent.set_limits(search_limits);
+                }
+            }
+        }).unwrap();
+    }
+
+    let addr = options.bind_addr();
+    let listener = TcpListener::bind(&addr).unwrap_or_else(|e| {
+        error!("Failed to bind {}: {}", addr, e);
+        std::process::exit(1);
+    });
+    info!("Listening on {}", addr);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            },
+        };
+
+        // every agent is busy and the queue is full
+        if let Err(TrySendError::Full(mut stream)) = sender.try_send(stream) {
+            let response = "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nAccess-Control-Allow-Origin: *\r\n\r\n{\"error\": \"server busy\"}\n";
+            let _ = stream.write_all(response.as_bytes());
+            state.metrics.request_rejected();
+            warn!("Server busy, turned away {}", stream.peer_addr().map_or("unknown peer".to_string(), |addr| addr.to_string()));
+        }
+    }
+}
+
+// a request being answered, with what gets logged and counted once it is done
+struct Exchange {
+    stream: TcpStream,
+    method: String,
+    path: String,
+    route: &'static str,
+    position: Option<String>,
+    // status code of the response sent, 0 before one is
+    status: u16,
+    stats: Option<SearchStats>,
+    cached: bool,
+    // the search ran out of budget and answered with its best move so far
+    timed_out: bool,
+}
+
+const CORS: &str = "Access-Control-Allow-Origin: *\r\n";
+// status logged for requests whose client went away before the answer, as nginx does
+const CLIENT_CLOSED: u16 = 499;
+
+impl Exchange {
+    fn send(&mut self, status: &str, headers: &str, body: &str) {
+        self.status = status.split(' ').next().and_then(|code| code.parse().ok()).unwrap_or(0);
+        let response = format!("HTTP/1.1 {}\r\n{}\r\n{}", status, headers, body);
+        let _ = self.stream.write_all(response.as_bytes());
+        debug!("Response: {:?}", response);
+    }
+
+    fn respond(&mut self, status: &str, body: &str) {
+        self.send(status, &format!("Content-Type: application/json\r\n{}", CORS), &format!("{}\n", body));
+    }
+
+    fn respond_error(&mut self, status: &str, error: &str) {
+        self.respond(status, &serde_json::json!({ "error": error }).to_string());
+    }
+}
+
+fn handle_connection(agent: &mut Agent, state: &ServerState, stream: TcpStream) {
+    let start = Instant::now();
+    let id = state.next_request.fetch_add(1, Ordering::Relaxed);
+    let mut exchange = Exchange {
+        stream,
+        method: String::new(),
+        path: String::new(),
+        route: "other",
+        position: None,
+        status: 0,
+        stats: None,
+        cached: false,
+        timed_out: false,
+    };
+
+    serve(agent, state, &mut exchange);
+    if exchange.method.is_empty() { return; }
+
+    let latency = start.elapsed();
+    let status = if exchange.status == 0 { CLIENT_CLOSED } else { exchange.status };
+    state.metrics.observe_request(exchange.route, status, latency);
+
+    let stats = exchange.stats.unwrap_or_default();
+    info!(
+        "request={} method={} path={} route={} position={} status={} latency_ms={:.1} nodes={} book_hit={} cached={} timed_out={}",
+        id,
+        exchange.method,
+        exchange.path,
+        exchange.route,
+        exchange.position.as_deref().unwrap_or("-"),
+        status,
+        latency.as_secs_f64() * 1000.0,
+        stats.nodes,
+        stats.book_hits > 0,
+        exchange.cached,
+        exchange.timed_out,
+    );
+}
+
+fn route_name(path: &[&str]) -> &'static str {
+    match path {
+        ["", "metrics"] => "metrics",
+        ["", "health"] => "health",
+        ["", "ready"] => "ready",
+        ["", "info"] => "info",
+        ["", "rpc"] => "rpc",
+        ["", "api", "history", ..] => "history",
+        ["", "api", "games", ..] => "games",
+        ["", "api", "analyze", ..] => "analyze",
+        ["", "api", "cache", ..] => "cache",
+        ["", "api", "stream", ..] => "stream",
+        ["", "api", "batch", ..] => "batch",
+        ["", "api", ..] => "solve",
+        _ => "other",
+    }
+}
+
+// answers 429 when the client has used up its allowance
+fn rate_limited(state: &ServerState, exchange: &mut Exchange) -> bool {
+    if let (Some(limiter), Ok(addr)) = (&state.limiter, exchange.stream.peer_addr()) {
+        if let Err(retry_after) = limiter.lock().unwrap().check(addr.ip(), Instant::now()) {
+            let headers = format!("Retry-After: {}\r\nContent-Type: application/json\r\n{}", retry_after.as_secs_f64().ceil() as u64, CORS);
+            exchange.send("429 Too Many Requests", &headers, "{\"error\": \"too many requests\"}\n");
+            state.metrics.request_limited();
+            debug!("Rate limited {}", addr);
+            return true;
+        }
+    }
+
+    false
+}
+
+// counts a request that may search `searches` boards as that many requests,
+// of which `rate_limited` already took one
+fn charge(state: &ServerState, exchange: &Exchange, searches: usize) {
+    if let (Some(limiter), Ok(addr)) = (&state.limiter, exchange.stream.peer_addr()) {
+        limiter.lock().unwrap().charge(addr.ip(), searches.saturating_sub(1) as f64, Instant::now());
+    }
+}
+
+fn serve(agent: &mut Agent, state: &ServerState, exchange: &mut Exchange) {
+    // don't let a silent client hold on to an agent
+    exchange.stream.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+
+    let mut buf_reader = BufReader::new(exchange.stream.try_clone().unwrap());
+    let mut head = buf_reader.by_ref().take(MAX_HEADER_SIZE);
+    let mut http_req = Vec::new();
+    let mut complete = false;
+    for line in head.by_ref().lines().map_while(Result::ok) {
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        http_req.push(line);
+    }
+    let too_large = !complete && head.limit() == 0;
+
+    trace!("Request: {:?}", http_req);
+    if http_req.is_empty() { return; }
+
+    let request_line = http_req[0].split(" ").collect::<Vec<&str>>();
+    exchange.method = request_line[0].to_string();
+    if request_line.len() < 2 {
+        exchange.respond_error("400 Bad Request", "malformed request");
+        return;
+    }
+    let method = request_line[0];
+    if too_large {
+        exchange.respond_error("431 Request Header Fields Too Large", "request headers too large");
+        return;
+    }
+
+    let target = request_line[1];
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let get_req = path.split("/").collect::<Vec<&str>>();
+    exchange.path = path.to_string();
+    exchange.route = route_name(&get_req);
+
+    // probes and scrapes stay answered for clients out of allowance
+    if !matches!(exchange.route, "health" | "ready" | "metrics") && rate_limited(state, exchange) { return; }
+
+    // allow browsers to post JSON from another origin
+    if method == "OPTIONS" {
+        exchange.send("204 No Content", &format!("{}Access-Control-Allow-Methods: GET, POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\n", CORS), "");
+        return;
+    }
+
+    let content_length = http_req.iter()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let max_body_size = if exchange.route == "batch" { MAX_BATCH_BODY_SIZE } else { MAX_BODY_SIZE };
+    if content_length > max_body_size {
+        exchange.respond_error("413 Payload Too Large", "request body too large");
+        return;
+    }
+    let mut body = vec![0; content_length];
+    if buf_reader.read_exact(&mut body).is_err() {
+        exchange.respond_error("400 Bad Request", "incomplete request body");
+        return;
+    }
+
+    let with_stats = query.split('&').any(|param| param == "stats" || param == "stats=true");
+
+    if method == "GET" && exchange.route == "metrics" {
+        let cache = state.cache.stats();
+        let sessions = state.sessions.lock().unwrap().len();
+        let metrics = state.metrics.render(cache, sessions);
+        exchange.send("200 OK", "Content-Type: text/plain; version=0.0.4\r\n", &metrics);
+        return;
+    }
+
+    if method == "GET" && exchange.route == "health" {
+        exchange.respond("200 OK", "{\"status\": \"ok\"}");
+        return;
+    }
+
+    // ready once the book, when one is configured, has loaded and every agent is up
+    if method == "GET" && exchange.route == "ready" {
+        let book = state.book_path.is_none() || state.book.get().is_some();
+        let warm = state.warm.load(Ordering::Relaxed);
+        let ready = book && warm == state.workers;
+        let status = if ready { "200 OK" } else { "503 Service Unavailable" };
+        exchange.respond(status, &serde_json::json!({ "ready": ready, "book": book, "agents": warm, "workers": state.workers }).to_string());
+        return;
+    }
+
+    if method == "GET" && exchange.route == "info" {
+        let db = state.book.get();
+        let info = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "width": WIDTH,
+            "height": HEIGHT,
+            "book": { "path": state.book_path, "loaded": db.is_some(), "depth": db.map(|db| db.depth()) },
+            "table_size": state.table_size.load(Ordering::Relaxed),
+            "workers": state.workers,
+            "cache_size": state.cache_size,
+        });
+        exchange.respond("200 OK", &info.to_string());
+        return;
+    }
+
+    // JSON-RPC, see `lib::rpc`; GET describes the methods
+    if exchange.route == "rpc" {
+        match method {
+            "GET" => exchange.respond("200 OK", &rpc::schema().to_string()),
+            "POST" => {
+                // a request costs as many requests as boards it may search
+                let request = String::from_utf8_lossy(&body);
+                charge(state, exchange, rpc::searches(&request));
+                let (response, disconnected) = until_disconnect(agent, exchange, |agent| rpc::handle(agent, &request));
+                if disconnected {
+                    state.metrics.search_cancelled();
+                    warn!("Client disconnected, JSON-RPC request cancelled");
+                    return;
+                }
+                match response {
+                    Some(response) => exchange.respond("200 OK", &response),
+                    None => exchange.send("204 No Content", CORS, ""),
+                }
+            },
+            _ => exchange.respond_error("405 Method Not Allowed", "use GET or POST"),
+        }
+        return;
+    }
+
+    if get_req.len() < 2 || get_req[1] != "api" {
+        exchange.respond_error("404 Not Found", "no such endpoint");
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "history" {
+        let history = match state.history {
+            Some(ref history) => history.lock().unwrap(),
+            None => return exchange.respond_error("404 Not Found", "games are not being recorded"),
+        };
+
+        // GET /api/history?limit=<n> lists recent games, GET /api/history/<moves>
+        // how games went on from a position
+        if get_req.len() < 4 || get_req[3].is_empty() {
+            let limit = query.split('&').find_map(|param| param.strip_prefix("limit=")).and_then(|limit| limit.parse().ok()).unwrap_or(RECENT_GAMES);
+            exchange.respond("200 OK", &serde_json::to_string(&history.recent(limit)).unwrap());
+        } else {
+            exchange.position = Some(get_req[3].to_string());
+            match Board::from_position(get_req[3]) {
+                Ok(board) => exchange.respond("200 OK", &serde_json::to_string(&history.position_stats(&board)).unwrap()),
+                Err(e) => exchange.respond_error("400 Bad Request", &e.to_string()),
+            }
+        }
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "games" {
+        games(agent, state, method, &get_req[3..], &body, exchange);
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "batch" {
+        if method != "POST" { return exchange.respond_error("405 Method Not Allowed", "use POST"); }
+
+        let format = query.split('&').find_map(|param| param.strip_prefix("format=")).unwrap_or("jsonl");
+        match (format.parse::<BatchFormat>(), batch::parse_positions(&String::from_utf8_lossy(&body))) {
+            (Err(e), _) => exchange.respond_error("400 Bad Request", &e),
+            (_, Err(e)) => exchange.respond_error("400 Bad Request", &format!("invalid positions: {}", e)),
+            (_, Ok(positions)) if positions.len() > MAX_BATCH_POSITIONS => {
+                exchange.respond_error("413 Payload Too Large", &format!("at most {} positions per batch", MAX_BATCH_POSITIONS));
+            },
+            (Ok(format), Ok(positions)) => {
+                // a batch costs as many requests as it has positions
+                charge(state, exchange, positions.len());
+                batch_solve(state, &positions, format, exchange);
+            },
+        }
+        return;
+    }
+
+    if method != "GET" {
+        exchange.respond_error("400 Bad Request", "use GET");
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "analyze" {
+        let pos = if get_req.len() < 4 { "" } else { get_req[3] };
+        exchange.position = Some(pos.to_string());
+        match Board::from_position(pos).map(Analysis::new) {
+            Ok(Some(analysis)) => exchange.respond("200 OK", &serde_json::to_string(&analysis).unwrap()),
+            Ok(None) => exchange.respond_error("400 Bad Request", "game is over"),
+            Err(e) => exchange.respond_error("400 Bad Request", &e.to_string()),
+        }
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "cache" {
+        let stats = state.cache.stats();
+        let mut json = serde_json::to_value(stats).unwrap();
+        json["hit_rate"] = stats.hit_rate().into();
+        exchange.respond("200 OK", &json.to_string());
+        return;
+    }
+
+    if get_req.len() >= 3 && get_req[2] == "stream" {
+        let pos = if get_req.len() < 4 { "" } else { get_req[3] };
+        exchange.position = Some(pos.to_string());
+        match Board::from_position(pos) {
+            Ok(board) if board.status().is_over() => exchange.respond_error("400 Bad Request", "game is over"),
+            Ok(board) => stream_analysis(agent, state, board, exchange),
+            Err(e) => exchange.respond_error("400 Bad Request", &e.to_string()),
+        }
+        return;
+    }
+
+    let pos = if get_req.len() < 3 { "" } else { get_req[2] };
+    exchange.position = Some(pos.to_string());
+    let board = Board::from_position(pos);
+    match board {
+        // nothing left to solve on a finished board
+        Ok(board) if board.status().is_over() => exchange.respond_error("400 Bad Request", "game is over"),
+        Ok(board) => {
+            let (scores, stats) = match solve(agent, state, board, exchange) {
+                Some(Solved::Exact(scores, stats)) => (scores, stats),
+                Some(Solved::Partial(cancelled)) => {
+                    let body = serde_json::json!({
+                        "col": agent::best_so_far(&board, &cancelled),
+                        "score": null,
+                        "min": cancelled.min,
+                        "max": cancelled.max,
+                        "scores": cancelled.scores,
+                        "timeout": true,
+                        "cached": false,
+                    });
+                    exchange.respond("200 OK", &body.to_string());
+                    return;
+                },
+                None => return,
+            };
+
+            let (col, score) = agent::best_column(&scores).unwrap();
+            let cached = stats.is_none();
+            let stats = match stats {
+                Some(stats) if with_stats => format!(", \"stats\": {}", serde_json::to_string(&stats).unwrap()),
+                _ => String::new(),
+            };
+            exchange.respond("200 OK", &format!("{{\"col\": {}, \"score\": {}, \"cached\": {}{}}}", col, score, cached, stats));
+        },
+        Err(e) => exchange.respond_error("400 Bad Request", &e.to_string()),
+    }
+}
+
+// streams a line per position as it is solved, with as many threads as there
+// are workers and the same table size and budget per position
+fn batch_solve(state: &ServerState, positions: &[BatchPosition], format: BatchFormat, exchange: &mut Exchange) {
+    let pool = match state.batch_pool.try_lock() {
+        Ok(pool) => pool,
+        Err(_) => return exchange.respond_error("503 Service Unavailable", "another batch is being solved"),
+    };
+
+    let content_type = match format {
+        BatchFormat::Csv => "text/csv",
+        BatchFormat::Jsonl => "application/x-ndjson",
+    };
+    exchange.send("200 OK", &format!("Content-Type: {}\r\n{}", content_type, CORS), "");
+
+    // stop solving once the client goes away
+    let token = CancellationToken::new();
+    let options = BatchOptions { limits: state.search_limits, budget: BATCH_BUDGET, cancel_token: Some(token.clone()), ..Default::default() };
+
+    let mut total = SearchStats::default();
+    let mut out = BufWriter::new(exchange.stream.try_clone().unwrap());
+    let _ = format.write_header(&mut out);
+    pool.solve(positions, &options, |result| {
+        total.nodes += result.nodes;
+        total.elapsed += result.elapsed;
+        if format.write(&mut out, &result).and_then(|_| out.flush()).is_err() { token.cancel(); }
+    });
+
+    if token.is_cancelled() {
+        state.metrics.search_cancelled();
+        warn!("Client disconnected, batch cancelled after {} nodes", total.nodes);
+    }
+    exchange.stats = Some(total);
+}
+
+enum Solved {
+    // scores of every column, with the search stats unless they came from the cache
+    Exact(ColumnScores, Option<SearchStats>),
+    // the search ran out of budget, with the scores of the columns it got through
+    Partial(Cancelled),
+}
+
+// scores of every column of `board`, from the cache or solved by `agent`
+// within the request budget; None if the client went away
+fn solve(agent: &mut Agent, state: &ServerState, board: Board, exchange: &mut Exchange) -> Option<Solved> {
+    let cached = state.cache.get(&board);
+    if let Some(scores) = cached {
+        exchange.cached = true;
+        return Some(Solved::Exact(scores, None));
+    }
+
+    let (result, disconnected) = until_disconnect(agent, exchange, |agent| agent.column_scores(board));
+    match result {
+        Ok((scores, stats)) => {
+            state.cache.insert(&board, scores);
+            state.metrics.observe_search(&stats);
+            exchange.stats = Some(stats);
+            Some(Solved::Exact(scores, Some(stats)))
+        },
+        Err(cancelled) => stopped(state, disconnected, &cancelled, exchange).then_some(Solved::Partial(cancelled)),
+    }
+}
+
+// runs `search` with `agent` stopping if the client goes away before the
+// answer is ready, and whether it did
+fn until_disconnect<R>(agent: &mut Agent, exchange: &mut Exchange, search: impl FnOnce(&mut Agent) -> R) -> (R, bool) {
+    let token = CancellationToken::new();
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = watch_disconnect(&exchange.stream, token.clone(), done.clone());
+    agent.set_cancel_token(Some(token.clone()));
+
+    let result = search(agent);
+    // before shutting the stream down makes it look closed
+    let disconnected = token.is_cancelled();
+    agent.set_cancel_token(None);
+    done.store(true, Ordering::Relaxed);
+    let _ = exchange.stream.shutdown(Shutdown::Read);
+    watcher.join().unwrap();
+
+    (result, disconnected)
+}
+
+// accounts for a search that stopped early, true if it ran out of budget
+// rather than losing its client
+fn stopped(state: &ServerState, disconnected: bool, cancelled: &Cancelled, exchange: &mut Exchange) -> bool {
+    exchange.stats = Some(SearchStats { nodes: cancelled.nodes, elapsed: cancelled.elapsed, ..Default::default() });
+    if disconnected {
+        state.metrics.search_cancelled();
+        warn!("Client disconnected, search cancelled after {} nodes", cancelled.nodes);
+        return false;
+    }
+
+    state.metrics.search_timed_out();
+    exchange.timed_out = true;
+    warn!("Search out of budget after {} nodes in {:.1?}, answering with the best move so far", cancelled.nodes, cancelled.elapsed);
+    true
+}
+
+#[derive(Deserialize)]
+struct NewGame {
+    // the human's side, the agent moves first when it is `second`
+    #[serde(default = "first_player")]
+    side: Player,
+    #[serde(default)]
+    difficulty: Difficulty,
+}
+
+fn first_player() -> Player {
+    Player::First
+}
+
+#[derive(Deserialize)]
+struct Move {
+    col: usize,
+}
+
+// POST /api/games                   {"side": "first", "difficulty": "hard"}
+// GET  /api/games/<id>
+// POST /api/games/<id>/moves        {"col": 3}
+// POST /api/games/<id>/undo
+// POST /api/games/<id>/resign
+fn games(agent: &mut Agent, state: &ServerState, method: &str, route: &[&str], body: &[u8], exchange: &mut Exchange) {
+    let result = match (method, route) {
+        ("POST", []) | ("POST", [""]) => {
+            let new_game: NewGame = match parse_body(body) {
+                Ok(new_game) => new_game,
+                Err(e) => return exchange.respond_error("400 Bad Request", &e),
+            };
+            state.sessions.lock().unwrap().create(new_game.side, new_game.difficulty).map(|session| session.id().to_string())
+        },
+        ("GET", [id]) => Ok(id.to_string()),
+        ("POST", [id, "moves"]) => {
+            let mv: Move = match parse_body(body) {
+                Ok(mv) => mv,
+                Err(e) => return exchange.respond_error("400 Bad Request", &e),
+            };
+            state.sessions.lock().unwrap().get_mut(id).and_then(|session| session.play(mv.col)).map(|_| id.to_string())
+        },
+        ("POST", [id, "undo"]) => state.sessions.lock().unwrap().get_mut(id).and_then(|session| session.undo()).map(|_| id.to_string()),
+        ("POST", [id, "resign"]) => state.sessions.lock().unwrap().get_mut(id).and_then(|session| session.resign()).map(|_| id.to_string()),
+        _ => return exchange.respond_error("404 Not Found", "no such endpoint"),
+    };
+
+    let id = match result {
+        Ok(id) => id,
+        Err(e) => return exchange.respond_error(session_status(e), &e.to_string()),
+    };
+
+    // reply for the agent, without holding the lock while solving
+    let agent_turn = state.sessions.lock().unwrap().get_mut(&id).ok().filter(|session| session.agent_to_move()).map(|session| session.game().board());
+    if let Some(board) = agent_turn {
+        // pick among the columns solved in time, or else play the best guess without a score
+        let reply = match solve(agent, state, board, exchange) {
+            Some(Solved::Exact(scores, _)) => Ok(scores),
+            Some(Solved::Partial(cancelled)) if cancelled.scores.iter().any(Option::is_some) => Ok(cancelled.scores),
+            Some(Solved::Partial(cancelled)) => Err(agent::best_so_far(&board, &cancelled) as usize),
+            None => return,
+        };
+
+        let mut sessions = state.sessions.lock().unwrap();
+        if let Ok(session) = sessions.get_mut(&id) {
+            // another request may have moved on in the meantime
+            if session.game().board() == board {
+                match reply {
+                    Ok(scores) => session.play_agent(&scores),
+                    Err(col) => session.play_agent_guess(col),
+                }.unwrap();
+            }
+        }
+    }
+
+    let (game_state, finished) = {
+        let mut sessions = state.sessions.lock().unwrap();
+        match sessions.get_mut(&id) {
+            Ok(session) => {
+                let finished = if session.take_finished() { Some(GameRecord::from_session(session)) } else { None };
+                (Ok(session.state()), finished)
+            },
+            Err(e) => (Err(e), None),
+        }
+    };
+
+    if let (Some(history), Some(record)) = (&state.history, finished) {
+        if let Err(e) = history.lock().unwrap().record(record) {
+            error!("Failed to record game {}: {}", id, e);
+        }
+    }
+
+    match game_state {
+        Ok(game_state) => {
+            exchange.position = Some(game_state.position.clone());
+            exchange.respond("200 OK", &serde_json::to_string(&game_state).unwrap());
+        },
+        Err(e) => exchange.respond_error(session_status(e), &e.to_string()),
+    }
+}
+
+fn session_status(e: SessionError) -> &'static str {
+    match e {
+        SessionError::NotFound => "404 Not Found",
+        SessionError::TooManyGames => "503 Service Unavailable",
+        SessionError::InvalidColumn(_) | SessionError::ColumnFull(_) => "400 Bad Request",
+        SessionError::NotYourTurn | SessionError::GameOver | SessionError::NothingToUndo => "409 Conflict",
+    }
+}
+
+// an empty body reads as an empty object, so every field takes its default
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    let body = if body.iter().all(u8::is_ascii_whitespace) { b"{}".as_slice() } else { body };
+    serde_json::from_slice(body).map_err(|e| format!("invalid request body: {}", e))
+}
+
+// pushes search progress to the client as server-sent events: `progress`
+// with the narrowing score bounds of the column being solved, `column` once
+// its score is proven, then a final `result` with the best move
+fn stream_analysis(agent: &mut Agent, state: &ServerState, board: Board, exchange: &mut Exchange) {
+    exchange.send("200 OK", &format!("Content-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}", CORS), "");
+
+    let token = CancellationToken::new();
+    let observer = Arc::new(EventStream { stream: Mutex::new(exchange.stream.try_clone().unwrap()), token: token.clone() });
+
+    let cached = state.cache.get(&board);
+    if let Some(scores) = cached {
+        exchange.cached = true;
+        for (col, score) in scores.iter().enumerate() {
+            if let Some(score) = score { observer.on_column(col as u8, *score); }
+        }
+        let (col, score) = agent::best_column(&scores).unwrap();
+        let result = serde_json::json!({ "col": col, "score": score, "scores": scores, "cached": true });
+        observer.send("result", &result.to_string());
+        debug!("Streamed cached result: {}", result);
+        return;
+    }
+
+    agent.set_observer(Some(observer.clone()));
+    agent.set_cancel_token(Some(token.clone()));
+
+    let result = agent.column_scores(board);
+    agent.set_observer(None);
+    agent.set_cancel_token(None);
+
+    match result {
+        Ok((scores, stats)) => {
+            state.cache.insert(&board, scores);
+            state.metrics.observe_search(&stats);
+            exchange.stats = Some(stats);
+            let (col, score) = agent::best_column(&scores).unwrap();
+            let result = serde_json::json!({ "col": col, "score": score, "scores": scores, "cached": false, "stats": stats });
+            observer.send("result", &result.to_string());
+            debug!("Streamed result: {}", result);
+        },
+        Err(cancelled) => {
+            if stopped(state, token.is_cancelled(), &cancelled, exchange) {
+                let col = agent::best_so_far(&board, &cancelled);
+                let result = serde_json::json!({ "col": col, "score": null, "min": cancelled.min, "max": cancelled.max, "scores": cancelled.scores, "timeout": true, "cached": false });
+                observer.send("result", &result.to_string());
+            }
+        },
+    }
+}
+
+struct EventStream {
+    stream: Mutex<TcpStream>,
+    token: CancellationToken,
+}
+
+impl EventStream {
+    fn send(&self, event: &str, data: &str) {
+        let message = format!("event: {}\ndata: {}\n\n", event, data);
+        if self.stream.lock().unwrap().write_all(message.as_bytes()).is_err() {
+            self.token.cancel();
+        }
+    }
+}
+
+impl SearchObserver for EventStream {
+    fn on_progress(&self, progress: &Progress) {
+        self.send("progress", &serde_json::to_string(progress).unwrap());
+    }
+
+    fn on_column(&self, col: u8, score: i8) {
+        self.send("column", &serde_json::json!({ "col": col, "score": score }).to_string());
+    }
+}
+
+// cancels `token` once the peer closes the connection, until `done` is set
+fn watch_disconnect(stream: &TcpStream, token: CancellationToken, done: Arc<AtomicBool>) -> JoinHandle<()> {
+    let stream = stream.try_clone().unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+    thread::spawn(move || {
+        let mut buf = [0; 1];
+        while !done.load(Ordering::Relaxed) {
+            match stream.peek(&mut buf) {
+                Ok(0) => {
+                    token.cancel();
+                    return;
+                },
+                Ok(_) => thread::sleep(Duration::from_millis(50)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+                Err(_) => {
+                    token.cancel();
+                    return;
+                },
+            }
+        }
+    })
+}
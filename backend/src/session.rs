@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+use crate::board::{GameStatus, Player, PositionError, WIDTH};
+use crate::cache::ColumnScores;
+use crate::game::Game;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    #[default]
+    Hard,
+}
+
+impl Difficulty {
+    // how far below the best score a move the agent picks may be
+    fn tolerance(self) -> i8 {
+        match self {
+            Difficulty::Easy => 8,
+            Difficulty::Medium => 3,
+            Difficulty::Hard => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionError {
+    NotFound,
+    TooManyGames,
+    InvalidColumn(usize),
+    ColumnFull(usize),
+    NotYourTurn,
+    GameOver,
+    NothingToUndo,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::NotFound => write!(f, "no such game"),
+            SessionError::TooManyGames => write!(f, "too many games in progress"),
+            SessionError::InvalidColumn(col) => write!(f, "invalid column {}", col),
+            SessionError::ColumnFull(col) => write!(f, "column {} is full", col),
+            SessionError::NotYourTurn => write!(f, "not your turn"),
+            SessionError::GameOver => write!(f, "game is over"),
+            SessionError::NothingToUndo => write!(f, "no move to undo"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ongoing,
+    Win,
+    Draw,
+    Resigned,
+}
+
+impl Outcome {
+    /// The outcome of a game nobody resigned, with its winner.
+    pub fn of(status: GameStatus) -> (Outcome, Option<Player>) {
+        match status {
+            GameStatus::Ongoing => (Outcome::Ongoing, None),
+            GameStatus::Win { player, .. } => (Outcome::Win, Some(player)),
+            GameStatus::Draw => (Outcome::Draw, None),
+        }
+    }
+}
+
+/// Everything a client needs to show a game.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GameState {
+    pub id: String,
+    /// Moves played so far as a position string.
+    pub position: String,
+    pub human: Player,
+    pub agent: Player,
+    pub difficulty: Difficulty,
+    pub side_to_move: Player,
+    pub outcome: Outcome,
+    pub winner: Option<Player>,
+    /// Tiles of the winning four as `(col, row)` pairs, row 0 at the bottom.
+    pub winning_cells: Vec<(usize, usize)>,
+    /// The agent's score for each of its moves, `None` for the human's.
+    pub scores: Vec<Option<i8>>,
+    pub can_undo: bool,
+}
+
+/// A game between a human and the agent.
+#[derive(Clone, Debug)]
+pub struct Session {
+    id: String,
+    game: Game,
+    human: Player,
+    difficulty: Difficulty,
+    scores: Vec<Option<i8>>,
+    resigned: bool,
+    recorded: bool,
+    last_active: Instant,
+}
+
+impl Session {
+    pub fn new(id: String, human: Player, difficulty: Difficulty) -> Session {
+        Session {
+            id,
+            game: Game::new(),
+            human,
+            difficulty,
+            scores: Vec::new(),
+            resigned: false,
+            recorded: false,
+            last_active: Instant::now(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn human(&self) -> Player {
+        self.human
+    }
+
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    /// The agent's score for each move played, `None` for the human's.
+    pub fn scores(&self) -> &[Option<i8>] {
+        &self.scores
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.resigned || self.game.board().status().is_over()
+    }
+
+    pub fn agent_to_move(&self) -> bool {
+        !self.is_over() && self.game.board().side_to_move() != self.human
+    }
+
+    pub fn play(&mut self, col: usize) -> Result<(), SessionError> {
+        if self.is_over() { return Err(SessionError::GameOver); }
+        if self.agent_to_move() { return Err(SessionError::NotYourTurn); }
+
+        self.game.play_col(col).map_err(|e| match e {
+            PositionError::ColumnFull(col) => SessionError::ColumnFull(col),
+            _ => SessionError::InvalidColumn(col),
+        })?;
+        self.scores.push(None);
+        Ok(())
+    }
+
+    /// Plays a move for the agent given the scores of every column, picking
+    /// at random among those close enough to the best for the difficulty.
+    pub fn play_agent(&mut self, scores: &ColumnScores) -> Result<usize, SessionError> {
+        if !self.agent_to_move() { return Err(SessionError::NotYourTurn); }
+
+        let best = scores.iter().flatten().max().copied().ok_or(SessionError::GameOver)?;
+        let candidates: Vec<usize> = (0..WIDTH)
+            .filter(|&col| scores[col].is_some_and(|score| score >= best - self.difficulty.tolerance()))
+            .collect();
+        let col = *candidates.choose(&mut rand::thread_rng()).unwrap();
+
+        self.game.play_col(col).map_err(|_| SessionError::ColumnFull(col))?;
+        self.scores.push(scores[col]);
+        Ok(col)
+    }
+
+    /// Plays `col` for the agent without a proven score, e.g. when its search
+    /// ran out of time.
+    pub fn play_agent_guess(&mut self, col: usize) -> Result<usize, SessionError> {
+        if !self.agent_to_move() { return Err(SessionError::NotYourTurn); }
+
+        self.game.play_col(col).map_err(|e| match e {
+            PositionError::ColumnFull(col) => SessionError::ColumnFull(col),
+            _ => SessionError::InvalidColumn(col),
+        })?;
+        self.scores.push(None);
+        Ok(col)
+    }
+
+    /// True the first time it is called once the game is over, so each game
+    /// gets recorded once even if play continues after an undo.
+    pub fn take_finished(&mut self) -> bool {
+        if !self.is_over() || self.recorded { return false; }
+        self.recorded = true;
+        true
+    }
+
+    pub fn resign(&mut self) -> Result<(), SessionError> {
+        if self.is_over() { return Err(SessionError::GameOver); }
+        self.resigned = true;
+        Ok(())
+    }
+
+    /// Takes back the human's last move along with the agent's reply.
+    pub fn undo(&mut self) -> Result<(), SessionError> {
+        if self.resigned { return Err(SessionError::GameOver); }
+
+        let human_moves = (0..self.game.moves().len()).filter(|&i| mover(i) == self.human).count();
+        if human_moves == 0 { return Err(SessionError::NothingToUndo); }
+
+        while self.game.undo().is_some() {
+            self.scores.pop();
+            if mover(self.game.moves().len()) == self.human { break; }
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> GameState {
+        let status = self.game.board().status();
+        let (outcome, winner) = if self.resigned { (Outcome::Resigned, Some(self.human.opponent())) } else { Outcome::of(status) };
+
+        GameState {
+            id: self.id.clone(),
+            position: self.game.to_position(),
+            human: self.human,
+            agent: self.human.opponent(),
+            difficulty: self.difficulty,
+            side_to_move: self.game.board().side_to_move(),
+            outcome,
+            winner,
+            winning_cells: status.winning_cells(),
+            scores: self.scores.clone(),
+            can_undo: !self.resigned && (0..self.game.moves().len()).any(|i| mover(i) == self.human),
+        }
+    }
+}
+
+// the player making the move at index `i`
+fn mover(i: usize) -> Player {
+    if i & 1 == 0 { Player::First } else { Player::Second }
+}
+
+/// Games in progress, forgotten once idle for longer than their time to live.
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration, capacity: usize) -> SessionStore {
+        SessionStore { sessions: HashMap::new(), ttl, capacity }
+    }
+
+    pub fn create(&mut self, human: Player, difficulty: Difficulty) -> Result<&mut Session, SessionError> {
+        self.purge_expired();
+        if self.sessions.len() >= self.capacity { return Err(SessionError::TooManyGames); }
+
+        let id = loop {
+            let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            if !self.sessions.contains_key(&id) { break id; }
+        };
+        Ok(self.sessions.entry(id.clone()).or_insert(Session::new(id, human, difficulty)))
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Result<&mut Session, SessionError> {
+        self.purge_expired();
+        let session = self.sessions.get_mut(id).ok_or(SessionError::NotFound)?;
+        session.last_active = Instant::now();
+        Ok(session)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    fn purge_expired(&mut self) {
+        let ttl = self.ttl;
+        self.sessions.retain(|_, session| session.last_active.elapsed() < ttl);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new("test".to_string(), Player::First, Difficulty::Hard);
+        assert_eq!(session.undo(), Err(SessionError::NothingToUndo));
+        assert_eq!(session.play(7), Err(SessionError::InvalidColumn(7)));
+
+        session.play(3).unwrap();
+        assert!(session.agent_to_move());
+        assert_eq!(session.play(3), Err(SessionError::NotYourTurn));
+
+        // hard always takes the best column
+        session.play_agent(&[Some(-2), Some(-1), Some(0), Some(1), None, Some(-3), Some(-4)]).unwrap();
+        assert_eq!(session.game().moves(), &[3, 3]);
+        assert_eq!(session.scores(), &[None, Some(1)]);
+
+        let only_center = [None, None, None, Some(0), None, None, None];
+        for _ in 0..2 {
+            session.play(3).unwrap();
+            session.play_agent(&only_center).unwrap();
+        }
+        assert_eq!(session.play(3), Err(SessionError::ColumnFull(3)));
+
+        session.undo().unwrap();
+        assert_eq!(session.game().moves().len(), 4);
+        assert_eq!(session.scores().len(), 4);
+        assert!(!session.agent_to_move());
+
+        assert!(!session.take_finished());
+        session.resign().unwrap();
+        assert!(session.take_finished());
+        assert!(!session.take_finished());
+        let state = session.state();
+        assert_eq!((state.outcome, state.winner), (Outcome::Resigned, Some(Player::Second)));
+        assert_eq!(session.play(0), Err(SessionError::GameOver));
+        assert_eq!(session.undo(), Err(SessionError::GameOver));
+    }
+
+    #[test]
+    fn test_agent_first() {
+        let mut session = Session::new("test".to_string(), Player::Second, Difficulty::Easy);
+        assert!(session.agent_to_move());
+
+        // easy may pick any column within 8 of the best score, leaving out only
+        // the one more than 8 below it
+        session.play_agent(&[Some(-18), Some(0), Some(-1), Some(1), Some(-2), Some(0), Some(-5)]).unwrap();
+        assert_ne!(session.game().moves()[0], 0);
+        assert_eq!(session.undo(), Err(SessionError::NothingToUndo));
+
+        // losing columns included, so it sometimes throws a drawn game
+        let mut picked = [false; WIDTH];
+        for _ in 0..100 {
+            let mut session = Session::new("test".to_string(), Player::Second, Difficulty::Easy);
+            picked[session.play_agent(&[None, None, None, Some(0), Some(-5), Some(-9), None]).unwrap()] = true;
+        }
+        assert_eq!(picked, [false, false, false, true, true, false, false]);
+
+        session.play(0).unwrap();
+        session.undo().unwrap();
+        assert_eq!(session.game().moves().len(), 1);
+        assert_eq!(session.state().position.len(), 1);
+
+        session.play(0).unwrap();
+        assert_eq!(session.play_agent_guess(9), Err(SessionError::InvalidColumn(9)));
+        session.play_agent_guess(3).unwrap();
+        assert_eq!(session.scores()[1..], [None, None]);
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = SessionStore::new(Duration::from_secs(60), 1);
+        let id = store.create(Player::First, Difficulty::Medium).unwrap().id().to_string();
+        assert_eq!(store.create(Player::First, Difficulty::Medium).unwrap_err(), SessionError::TooManyGames);
+        assert_eq!(store.get_mut(&id).unwrap().difficulty(), Difficulty::Medium);
+        assert_eq!(store.get_mut("missing").unwrap_err(), SessionError::NotFound);
+
+        let mut store = SessionStore::new(Duration::ZERO, 1);
+        let id = store.create(Player::First, Difficulty::Hard).unwrap().id().to_string();
+        assert_eq!(store.get_mut(&id).unwrap_err(), SessionError::NotFound);
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::ops::AddAssign;
+use std::time::Duration;
+use serde::{Serialize, Serializer};
+use schemars::JsonSchema;
+
+/// Counters collected while searching a position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, JsonSchema)]
+pub struct SearchStats {
+    pub nodes: u64,
+    pub tt_probes: u64,
+    pub tt_hits: u64,
+    /// Probes whose stored bound alone ended the search of a node.
+    pub tt_cutoffs: u64,
+    pub book_hits: u64,
+    /// How often the n-th move tried at a node caused a beta cutoff, a measure
+    /// of move ordering quality.
+    pub cutoff_index: [u64; 7],
+    /// Null window searches run while narrowing the score.
+    pub iterations: u32,
+    #[serde(rename = "elapsed_us", serialize_with = "serialize_micros")]
+    #[schemars(with = "u64")]
+    pub elapsed: Duration,
+}
+
+impl SearchStats {
+    pub fn tt_hit_rate(&self) -> f64 {
+        if self.tt_probes == 0 { 0.0 } else { self.tt_hits as f64 / self.tt_probes as f64 }
+    }
+
+    /// Share of beta cutoffs caused by the first move tried.
+    pub fn first_cutoff_rate(&self) -> f64 {
+        let cutoffs: u64 = self.cutoff_index.iter().sum();
+        if cutoffs == 0 { 0.0 } else { self.cutoff_index[0] as f64 / cutoffs as f64 }
+    }
+
+    pub fn nodes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 { 0.0 } else { self.nodes as f64 / secs }
+    }
+}
+
+impl AddAssign for SearchStats {
+    fn add_assign(&mut self, other: SearchStats) {
+        self.nodes += other.nodes;
+        self.tt_probes += other.tt_probes;
+        self.tt_hits += other.tt_hits;
+        self.tt_cutoffs += other.tt_cutoffs;
+        self.book_hits += other.book_hits;
+        for (count, other_count) in self.cutoff_index.iter_mut().zip(other.cutoff_index) {
+            *count += other_count;
+        }
+        self.iterations += other.iterations;
+        self.elapsed += other.elapsed;
+    }
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes in {:.2?} ({:.0} nodes/s), TT hits {:.1}%, first move cutoffs {:.1}%, book hits {}, {} iterations",
+            self.nodes,
+            self.elapsed,
+            self.nodes_per_sec(),
+            self.tt_hit_rate() * 100.0,
+            self.first_cutoff_rate() * 100.0,
+            self.book_hits,
+            self.iterations,
+        )
+    }
+}
+
+pub(crate) fn serialize_micros<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_micros() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = SearchStats { nodes: 10, tt_probes: 4, tt_hits: 1, cutoff_index: [3, 1, 0, 0, 0, 0, 0], ..Default::default() };
+        assert_eq!(stats.tt_hit_rate(), 0.25);
+        assert_eq!(stats.first_cutoff_rate(), 0.75);
+        assert_eq!(stats.nodes_per_sec(), 0.0);
+
+        stats += SearchStats { nodes: 5, tt_probes: 4, tt_hits: 3, cutoff_index: [0, 0, 4, 0, 0, 0, 0], elapsed: Duration::from_secs(3), ..Default::default() };
+        assert_eq!(stats.nodes, 15);
+        assert_eq!(stats.tt_hit_rate(), 0.5);
+        assert_eq!(stats.cutoff_index, [3, 1, 4, 0, 0, 0, 0]);
+        assert_eq!(stats.nodes_per_sec(), 5.0);
+        assert_eq!(SearchStats::default().first_cutoff_rate(), 0.0);
+    }
+}
@@ -2,34 +2,180 @@ use serde::{Serialize, Deserialize};
 
 // smallest prime number larger than 8 million (64mb table)
 pub const TABLE_SIZE: usize = 8_388_593;
+// keys are stored truncated to 32 bits, which stays unambiguous for the 49 bit
+// position keys as long as the table is odd sized and has at least 2^17 entries
+pub const MIN_TABLE_SIZE: usize = 1 << 17;
+/// Most entries a table should be asked for where the size comes from a user,
+/// 1GB of 8 byte entries.
+pub const MAX_TABLE_SIZE: usize = 1 << 27;
 
-// todo: maybe take table size as a param so it can be dynamically sized
-#[derive(Serialize, Deserialize, Clone)]
+// the flags of an entry hold its bound in the low two bits, 0 for an empty
+// entry, and its best column in the three above, 7 for none
+const BOUND_MASK: u8 = 0b11;
+const NO_COL: u8 = 7;
+
+/// What the score of an entry says about the position's score.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bound {
+    /// The position scores at least this much.
+    Lower = 1,
+    /// The position scores at most this much.
+    Upper = 2,
+    Exact = 3,
+}
+
+/// A search result stored in the transposition table, packed into 8 bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct Entry {
+    key: u32,
+    score: i8,
+    flags: u8,
+    depth: u8,
+    age: u8,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry { key: !0, score: 0, flags: 0, depth: 0, age: 0 };
+
+    pub fn score(&self) -> i8 {
+        self.score
+    }
+
+    pub fn bound(&self) -> Bound {
+        match self.flags & BOUND_MASK {
+            1 => Bound::Lower,
+            2 => Bound::Upper,
+            _ => Bound::Exact,
+        }
+    }
+
+    /// The column found best, or that caused a cutoff, numbered from 0.
+    pub fn best_col(&self) -> Option<u8> {
+        let col = self.flags >> 2;
+        if col == NO_COL { None } else { Some(col) }
+    }
+
+    /// Moves left to play in the position when it was searched.
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The age of the table when the entry was stored, see `TranspositionTable::next_age`.
+    pub fn age(&self) -> u8 {
+        self.age
+    }
+
+    fn is_empty(&self) -> bool {
+        self.flags & BOUND_MASK == 0
+    }
+}
+
+/// The results of searching positions, by position key.
+#[derive(Clone)]
 pub struct TranspositionTable {
+    entries: Vec<Entry>,
+    age: u8,
+}
+
+impl Default for TranspositionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TranspositionTable {
+    pub fn new() -> Self {
+        Self::with_size(TABLE_SIZE)
+    }
+
+    /// A table with room for at least `entries` positions, rounded up to the
+    /// next prime and to no less than `MIN_TABLE_SIZE`.
+    pub fn with_size(entries: usize) -> Self {
+        Self { entries: vec![Entry::EMPTY; next_prime(entries.max(MIN_TABLE_SIZE))], age: 0 }
+    }
+
+    /// Number of entries in the table.
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Stores the result of searching the position of `key`, replacing
+    /// whatever entry was in its place. An entry for the same position keeps
+    /// its best column when `best_col` is None.
+    pub fn set(&mut self, key: u64, score: i8, bound: Bound, best_col: Option<u8>, depth: u8) {
+        let index = key as usize % self.entries.len();
+        let entry = &mut self.entries[index];
+        let col = match best_col {
+            Some(col) => col,
+            None if entry.key == key as u32 && !entry.is_empty() => entry.flags >> 2,
+            None => NO_COL,
+        };
+
+        *entry = Entry { key: key as u32, score, flags: col << 2 | bound as u8, depth, age: self.age };
+    }
+
+    pub fn get(&self, key: u64) -> Option<Entry> {
+        let entry = self.entries[key as usize % self.entries.len()];
+        if entry.key == key as u32 && !entry.is_empty() {
+            return Some(entry);
+        }
+
+        None
+    }
+
+    /// Entries stored from now on get the next age, telling them apart from
+    /// those of earlier searches.
+    pub fn next_age(&mut self) {
+        self.age = self.age.wrapping_add(1);
+    }
+}
+
+/// Scores by position key, as stored in opening books.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScoreTable {
     keys: Vec<u32>,
     vals: Vec<i8>,
 }
 
-impl TranspositionTable {
+impl Default for ScoreTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScoreTable {
     pub fn new() -> Self {
+        Self::with_size(TABLE_SIZE)
+    }
+
+    /// A table with room for at least `entries` positions, sized like a
+    /// `TranspositionTable`.
+    pub fn with_size(entries: usize) -> Self {
+        let size = next_prime(entries.max(MIN_TABLE_SIZE));
         Self {
-            keys: vec![!0; TABLE_SIZE],
-            vals: vec![!0; TABLE_SIZE],
+            keys: vec![!0; size],
+            vals: vec![!0; size],
         }
     }
 
+    /// Number of entries in the table.
+    pub fn size(&self) -> usize {
+        self.keys.len()
+    }
+
     pub fn from(keys: Vec<u32>, vals: Vec<i8>) -> Self {
         Self { keys, vals }
     }
 
     pub fn set(&mut self, key: u64, value: i8) {
-        let index = key as usize % TABLE_SIZE;
+        let index = key as usize % self.keys.len();
         self.keys[index] = key as u32;
         self.vals[index] = value;
     }
 
     pub fn get(&self, key: u64) -> Option<i8> {
-        let index = key as usize % TABLE_SIZE;
+        let index = key as usize % self.keys.len();
         if self.keys[index] == key as u32 {
             return Some(self.vals[index]);
         }
@@ -37,6 +183,20 @@ impl TranspositionTable {
         None
     }
 
+    /// Every stored entry with its full key. The truncated key and the index
+    /// together give the key modulo 2^32 times the size, which covers any
+    /// position key as the size is an odd prime of at least 2^17.
+    pub fn entries(&self) -> impl Iterator<Item = (u64, i8)> + '_ {
+        let size = self.keys.len() as u128;
+        let inverse = pow_mod((1 << 32) % size, size - 2, size);
+        self.keys.iter().zip(self.vals.iter()).enumerate()
+            .filter(|(_, (&key, &val))| key != !0 || val != !0)
+            .map(move |(index, (&key, &val))| {
+                let high = (index as u128 + size - key as u128 % size) % size * inverse % size;
+                (((high << 32) + key as u128) as u64, val)
+            })
+    }
+
     pub fn keys(&self) -> Vec<u32> {
         self.keys.clone()
     }
@@ -45,3 +205,79 @@ impl TranspositionTable {
         self.vals.clone()
     }
 }
+
+// `base` to the power of `exp`, modulo `modulus`
+fn pow_mod(mut base: u128, mut exp: u128, modulus: u128) -> u128 {
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 { result = result * base % modulus; }
+        base = base * base % modulus;
+        exp >>= 1;
+    }
+    result
+}
+
+fn next_prime(n: usize) -> usize {
+    let is_prime = |n: usize| n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d));
+    (n..).find(|&n| is_prime(n)).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_with_size() {
+        assert_eq!(TranspositionTable::new().size(), TABLE_SIZE);
+        assert_eq!(TranspositionTable::with_size(8_000_000).size(), 8_000_009);
+        assert_eq!(TranspositionTable::with_size(0).size(), 131_101);
+        assert_eq!(ScoreTable::with_size(0).size(), 131_101);
+
+        let mut table = ScoreTable::with_size(0);
+        table.set(1 << 40, 5);
+        assert_eq!(table.get(1 << 40), Some(5));
+        assert_eq!(table.get((1 << 40) + table.size() as u64), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(std::mem::size_of::<Entry>(), 8);
+
+        let mut table = TranspositionTable::with_size(0);
+        let key = 1 << 40;
+        assert_eq!(table.get(key), None);
+
+        table.set(key, -18, Bound::Lower, Some(6), 42);
+        let entry = table.get(key).unwrap();
+        assert_eq!((entry.score(), entry.bound(), entry.best_col(), entry.depth(), entry.age()), (-18, Bound::Lower, Some(6), 42, 0));
+
+        // a later result for the position keeps its best column
+        table.next_age();
+        table.set(key, 3, Bound::Exact, None, 42);
+        let entry = table.get(key).unwrap();
+        assert_eq!((entry.score(), entry.bound(), entry.best_col(), entry.age()), (3, Bound::Exact, Some(6), 1));
+
+        // another position taking the entry has no best column yet
+        let other = key + table.size() as u64;
+        table.set(other, 0, Bound::Upper, None, 1);
+        assert_eq!(table.get(key), None);
+        assert_eq!(table.get(other).unwrap().best_col(), None);
+
+        // the empty entry's key is a valid one
+        let empty = !0u32 as u64;
+        assert_eq!(table.get(empty), None);
+        table.set(empty, 0, Bound::Upper, None, 0);
+        assert_eq!(table.get(empty).map(|entry| entry.bound()), Some(Bound::Upper));
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut table = ScoreTable::with_size(0);
+        let keys = [1 << 40, (1 << 48) + 12345, 7];
+        for (i, key) in keys.iter().enumerate() { table.set(*key, i as i8); }
+
+        let mut entries: Vec<(u64, i8)> = table.entries().collect();
+        entries.sort_by_key(|(_, val)| *val);
+        assert_eq!(entries, vec![(keys[0], 0), (keys[1], 1), (keys[2], 2)]);
+    }
+}
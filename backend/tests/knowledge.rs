@@ -0,0 +1,45 @@
+use lib::board::Board;
+use lib::agent::Agent;
+use lib::knowledge::prove;
+
+fn positions(filename: &str) -> Vec<(Board, i8)> {
+    std::fs::read_to_string(filename).unwrap()
+        .lines()
+        .map(|line| {
+            let parts = line.split(" ").collect::<Vec<&str>>();
+            (Board::from_position(parts[0]).unwrap(), parts[1].parse::<i8>().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn proofs_agree_with_scores() {
+    let mut proved = 0;
+    for filename in ["benchmarks/Test_L3_R1", "benchmarks/Test_L2_R1", "benchmarks/Test_L2_R2"] {
+        for (board, expected) in positions(filename) {
+            if let Some(proof) = prove(board) {
+                assert!(expected <= 0, "{} scores {} but:\n{}", board, expected, proof);
+                proved += 1;
+            }
+        }
+    }
+
+    assert!(proved > 0);
+}
+
+#[test]
+fn knowledge_keeps_scores_exact() {
+    for (board, expected) in positions("benchmarks/Test_L3_R1").into_iter().take(50) {
+        let mut agent = Agent::new(None).with_knowledge(true);
+        assert_eq!(agent.best_score(board).unwrap().0, expected);
+    }
+}
+
+// the rules are only tried in the opening, where these positions start
+#[test]
+fn knowledge_keeps_opening_scores_exact() {
+    for (board, expected) in positions("benchmarks/Test_L1_R1").into_iter().take(50) {
+        let mut agent = Agent::new(None).with_knowledge(true);
+        assert_eq!(agent.best_score(board).unwrap().0, expected);
+    }
+}